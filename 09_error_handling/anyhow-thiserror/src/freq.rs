@@ -0,0 +1,215 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+
+use crate::wordcounter::{read_lines, Segmentation, WordCountError};
+
+/// How words are normalised before they are counted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FreqOptions {
+    /// Counts `The` and `the` as the same word.
+    pub fold_case: bool,
+    /// Strips punctuation from the start and end of words, so that `end.`
+    /// and `"end` count as `end`. Punctuation inside a word, as in `don't`,
+    /// is kept.
+    pub strip_punctuation: bool,
+    /// Words to leave out, normalised like the words of the input.
+    pub stop_words: HashSet<String>,
+    pub segmentation: Segmentation,
+}
+
+impl FreqOptions {
+    /// The word `word` is counted as, if it is counted at all.
+    fn normalise(&self, word: &str) -> Option<String> {
+        let word = if self.strip_punctuation {
+            word.trim_matches(|c: char| !c.is_alphanumeric())
+        } else {
+            word
+        };
+        if word.is_empty() {
+            return None;
+        }
+        let word = if self.fold_case {
+            word.to_lowercase()
+        } else {
+            word.to_string()
+        };
+        if self.stop_words.contains(&word) {
+            return None;
+        }
+        Some(word)
+    }
+
+    /// Reads a list of stop words, separated by whitespace, and normalises
+    /// them like the words they are to match.
+    pub fn read_stop_words<R: Read>(&mut self, input: &mut R) -> Result<(), WordCountError> {
+        let mut words = vec![];
+        read_lines(input, |line| {
+            words.extend(
+                line.split_whitespace()
+                    .filter_map(|word| self.normalise(word)),
+            )
+        })?;
+        self.stop_words.extend(words);
+        Ok(())
+    }
+}
+
+/// How to print a frequency table.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// A count and a word on every line, like `uniq -c`.
+    #[default]
+    Text,
+    /// A `word,count` header and one row per word.
+    Csv,
+    /// An array of `{"word": ..., "count": ...}` objects.
+    Json,
+}
+
+/// The number of times every word occurs, across any number of inputs.
+#[derive(Debug, Clone, Default)]
+pub struct Frequencies {
+    options: FreqOptions,
+    counts: HashMap<String, u64>,
+}
+
+impl Frequencies {
+    pub fn new(options: FreqOptions) -> Frequencies {
+        Frequencies {
+            options,
+            counts: HashMap::new(),
+        }
+    }
+
+    /// Counts the words of `input`, split as the options say.
+    pub fn add<R: Read>(&mut self, input: &mut R) -> Result<(), WordCountError> {
+        let Frequencies { options, counts } = self;
+        read_lines(input, |line| {
+            for word in options
+                .segmentation
+                .words(line)
+                .filter_map(|word| options.normalise(word))
+            {
+                *counts.entry(word).or_default() += 1;
+            }
+        })
+    }
+
+    /// The `top` most frequent words, or all of them, from the most frequent
+    /// down. Words that occur equally often are in alphabetical order.
+    pub fn sorted(&self, top: Option<usize>) -> Vec<(&str, u64)> {
+        let mut words: Vec<_> = self
+            .counts
+            .iter()
+            .map(|(word, &count)| (word.as_str(), count))
+            .collect();
+        words.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        words.truncate(top.unwrap_or(words.len()));
+        words
+    }
+}
+
+/// Writes a table of words and their counts, as returned by
+/// `Frequencies::sorted()`.
+pub fn write_table<W: Write>(out: &mut W, words: &[(&str, u64)], format: Format) -> io::Result<()> {
+    match format {
+        Format::Text => {
+            let width = words
+                .first()
+                .map_or(0, |(_, count)| count.to_string().len());
+            for (word, count) in words {
+                writeln!(out, "{:>width$} {}", count, word, width = width)?;
+            }
+        }
+        Format::Csv => {
+            writeln!(out, "word,count")?;
+            for (word, count) in words {
+                if word.contains([',', '"']) {
+                    writeln!(out, "\"{}\",{}", word.replace('"', "\"\""), count)?;
+                } else {
+                    writeln!(out, "{},{}", word, count)?;
+                }
+            }
+        }
+        Format::Json => {
+            write!(out, "[")?;
+            for (i, (word, count)) in words.iter().enumerate() {
+                let separator = if i == 0 { "" } else { "," };
+                write!(
+                    out,
+                    "{}\n  {{\"word\": \"{}\", \"count\": {}}}",
+                    separator,
+                    json_escape(word),
+                    count
+                )?;
+            }
+            writeln!(out, "{}]", if words.is_empty() { "" } else { "\n" })?;
+        }
+    }
+    Ok(())
+}
+
+/// Escapes `text` for a JSON string.
+fn json_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "The cat saw the dog.\nThe dog, the \"cat\" and don't\n";
+
+    fn table(options: FreqOptions, top: Option<usize>, format: Format) -> String {
+        let mut freq = Frequencies::new(options);
+        freq.add(&mut TEXT.as_bytes()).unwrap();
+        let mut out = vec![];
+        write_table(&mut out, &freq.sorted(top), format).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn normalises_words() {
+        let plain = table(FreqOptions::default(), Some(3), Format::Text);
+        assert_eq!(plain, "2 The\n2 the\n1 \"cat\"\n");
+
+        let mut options = FreqOptions {
+            fold_case: true,
+            strip_punctuation: true,
+            ..FreqOptions::default()
+        };
+        options
+            .read_stop_words(&mut "THE and\n".as_bytes())
+            .unwrap();
+        assert_eq!(
+            table(options, None, Format::Text),
+            "2 cat\n2 dog\n1 don't\n1 saw\n"
+        );
+    }
+
+    #[test]
+    fn writes_csv_and_json() {
+        let csv = table(FreqOptions::default(), Some(6), Format::Csv);
+        assert_eq!(
+            csv,
+            "word,count\nThe,2\nthe,2\n\"\"\"cat\"\"\",1\nand,1\ncat,1\n\"dog,\",1\n"
+        );
+
+        let json = table(FreqOptions::default(), Some(2), Format::Json);
+        assert_eq!(
+            json,
+            "[\n  {\"word\": \"The\", \"count\": 2},\n  {\"word\": \"the\", \"count\": 2}\n]\n"
+        );
+        assert_eq!(json_escape("\"a\\b\"\u{7}"), "\\\"a\\\\b\\\"\\u0007");
+        assert_eq!(table(FreqOptions::default(), Some(0), Format::Json), "[]\n");
+    }
+}
@@ -1,22 +1,492 @@
+pub mod freq;
 pub mod wordcounter;
 
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::process::ExitCode;
 
-use crate::wordcounter::count_words;
-use anyhow::Context;
+use crate::freq::{write_table, Format, FreqOptions, Frequencies};
+use crate::wordcounter::{count_with, Counts, Segmentation};
+use anyhow::{anyhow, bail, Context};
+
+/// The counts to print. Like `wc`, they are always printed in the order of
+/// these fields, whatever the order of the flags asking for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fields {
+    pub lines: bool,
+    pub words: bool,
+    pub chars: bool,
+    pub bytes: bool,
+    pub max_line_length: bool,
+}
+
+impl Default for Fields {
+    /// Lines, words and bytes, as printed when no counts are asked for.
+    fn default() -> Fields {
+        Fields {
+            lines: true,
+            words: true,
+            chars: false,
+            bytes: true,
+            max_line_length: false,
+        }
+    }
+}
+
+impl Fields {
+    fn none() -> Fields {
+        Fields {
+            lines: false,
+            words: false,
+            chars: false,
+            bytes: false,
+            max_line_length: false,
+        }
+    }
+
+    /// The selected counts, in output order.
+    pub fn values(&self, counts: &Counts) -> Vec<u64> {
+        [
+            (self.lines, counts.lines),
+            (self.words, counts.words),
+            (self.chars, counts.chars),
+            (self.bytes, counts.bytes),
+            (self.max_line_length, counts.max_line_length),
+        ]
+        .into_iter()
+        .filter(|&(selected, _)| selected)
+        .map(|(_, value)| value)
+        .collect()
+    }
+}
+
+/// The command line of `rwc`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Args {
+    pub fields: Fields,
+    /// The files to count, where `-` is standard input.
+    pub files: Vec<String>,
+    /// A file with the names of the files to count instead, separated by NUL
+    /// characters.
+    pub files0_from: Option<String>,
+    /// Print how often every word occurs instead of the counts.
+    pub freq: Option<FreqArgs>,
+    pub segmentation: Segmentation,
+}
+
+/// The options of `--freq`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FreqArgs {
+    pub fold_case: bool,
+    pub strip_punctuation: bool,
+    /// A file with words to leave out.
+    pub stop_words: Option<String>,
+    /// Print only this many of the most frequent words.
+    pub top: Option<usize>,
+    pub format: Format,
+}
+
+/// Parses the arguments after the program name. Short flags can be combined,
+/// as in `-lw`, `--` ends the flags, and the values of long options follow
+/// either a `=` or a space.
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> anyhow::Result<Args> {
+    let mut fields = Fields::none();
+    let mut files = vec![];
+    let mut files0_from = None;
+    let mut freq = false;
+    let mut freq_args = FreqArgs::default();
+    let mut segmentation = Segmentation::default();
+    let mut flags_done = false;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if flags_done || arg == "-" || !arg.starts_with('-') {
+            files.push(arg);
+            continue;
+        }
+        let (option, mut inline) = match arg.split_once('=') {
+            Some((option, value)) if arg.starts_with("--") => (option, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = || {
+            inline
+                .take()
+                .or_else(|| args.next())
+                .ok_or_else(|| anyhow!("option '{}' requires an argument", option))
+        };
+        match option {
+            "--files0-from" => files0_from = Some(value()?),
+            "--stop-words" => freq_args.stop_words = Some(value()?),
+            "--top" => {
+                let top = value()?;
+                let top = top
+                    .parse()
+                    .context(format!("invalid number of words '{}'", top))?;
+                freq_args.top = Some(top);
+            }
+            "--segmentation" => {
+                segmentation = match value()?.as_str() {
+                    "whitespace" => Segmentation::Whitespace,
+                    "unicode" => Segmentation::Unicode,
+                    other => bail!(
+                        "invalid segmentation '{}': use whitespace or unicode",
+                        other
+                    ),
+                }
+            }
+            "--format" => {
+                freq_args.format = match value()?.as_str() {
+                    "text" => Format::Text,
+                    "csv" => Format::Csv,
+                    "json" => Format::Json,
+                    format => bail!("invalid format '{}': use text, csv or json", format),
+                }
+            }
+            "--"
+            | "--lines"
+            | "--words"
+            | "--chars"
+            | "--bytes"
+            | "--max-line-length"
+            | "--freq"
+            | "--fold-case"
+            | "--strip-punctuation"
+                if inline.is_some() =>
+            {
+                bail!("option '{}' doesn't allow an argument", option)
+            }
+            "--" => flags_done = true,
+            "--lines" => fields.lines = true,
+            "--words" => fields.words = true,
+            "--chars" => fields.chars = true,
+            "--bytes" => fields.bytes = true,
+            "--max-line-length" => fields.max_line_length = true,
+            "--freq" => freq = true,
+            "--fold-case" => freq_args.fold_case = true,
+            "--strip-punctuation" => freq_args.strip_punctuation = true,
+            _ if arg.starts_with("--") => bail!("unrecognized option '{}'", arg),
+            _ => {
+                for flag in arg.chars().skip(1) {
+                    match flag {
+                        'l' => fields.lines = true,
+                        'w' => fields.words = true,
+                        'm' => fields.chars = true,
+                        'c' => fields.bytes = true,
+                        'L' => fields.max_line_length = true,
+                        _ => bail!("invalid option -- '{}'", flag),
+                    }
+                }
+            }
+        }
+    }
+
+    if let (Some(_), Some(file)) = (&files0_from, files.first()) {
+        bail!(
+            "extra operand '{}'\nfile operands cannot be combined with --files0-from",
+            file
+        );
+    }
+    if !freq && freq_args != FreqArgs::default() {
+        bail!("the word frequency options only work with --freq");
+    }
+    if fields == Fields::none() {
+        fields = Fields::default();
+    }
+    Ok(Args {
+        fields,
+        files,
+        files0_from,
+        freq: freq.then_some(freq_args),
+        segmentation,
+    })
+}
+
+/// Splits a list of NUL-terminated file names read from `list`. The last
+/// name does not need a NUL after it.
+pub fn parse_files0<R: Read>(mut input: R, list: &str) -> anyhow::Result<Vec<String>> {
+    let mut data = vec![];
+    input
+        .read_to_end(&mut data)
+        .context(format!("unable to read file names from '{}'", list))?;
+    if data.last() == Some(&0) {
+        data.pop();
+    }
+    if data.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut names = vec![];
+    for (i, name) in data.split(|&byte| byte == 0).enumerate() {
+        if name.is_empty() {
+            bail!("{}:{}: invalid zero-length file name", list, i + 1);
+        }
+        let name = String::from_utf8(name.to_vec()).context(format!(
+            "{}:{}: file name is not valid UTF-8",
+            list,
+            i + 1
+        ))?;
+        if name == "-" && list == "-" {
+            bail!("when reading file names from stdin, no file name of '-' allowed");
+        }
+        names.push(name);
+    }
+    Ok(names)
+}
+
+/// The files to count and whether to print their names: standard input is
+/// counted without a name if no files are given at all.
+fn inputs(args: &Args) -> anyhow::Result<(Vec<String>, bool)> {
+    match args.files0_from {
+        Some(ref list) if list == "-" => Ok((parse_files0(io::stdin().lock(), list)?, true)),
+        Some(ref list) => {
+            let file = File::open(list).context(format!("unable to open '{}'", list))?;
+            Ok((parse_files0(file, list)?, true))
+        }
+        None if args.files.is_empty() => Ok((vec!["-".to_string()], false)),
+        None => Ok((args.files.clone(), true)),
+    }
+}
+
+/// How wide `wc` makes every column: wide enough for the total size of the
+/// files, so that all rows line up. A lone count for a single file is not
+/// padded at all, and neither are counts for names read from standard input,
+/// as they are printed before the other names are known.
+fn column_width(args: &Args, files: &[String]) -> usize {
+    let single = files.len() == 1 && args.fields.values(&Counts::default()).len() == 1;
+    if single || args.files0_from.as_deref() == Some("-") {
+        return 1;
+    }
+    let mut minimum = 1;
+    let mut total = 0;
+    for filename in files {
+        // Standard input may well be a file, as in `rwc < words.txt`.
+        let path = if filename == "-" {
+            "/dev/stdin"
+        } else {
+            filename
+        };
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.is_file() => total += metadata.len(),
+            // The size of anything else is not known in advance.
+            Ok(_) => minimum = 7,
+            Err(_) => {}
+        }
+    }
+    total.to_string().len().max(minimum)
+}
+
+/// One row of output: the selected counts, right-aligned to `width`, and the
+/// name of the input.
+pub fn format_row(fields: &Fields, counts: &Counts, width: usize, name: &str) -> String {
+    let mut row = fields
+        .values(counts)
+        .iter()
+        .map(|value| format!("{:>width$}", value, width = width))
+        .collect::<Vec<_>>()
+        .join(" ");
+    if !name.is_empty() {
+        row.push(' ');
+        row.push_str(name);
+    }
+    row
+}
+
+/// Opens `filename` for reading, where `-` is standard input.
+fn open(filename: &str) -> anyhow::Result<Box<dyn Read>> {
+    if filename == "-" {
+        return Ok(Box::new(io::stdin().lock()));
+    }
+    let file = File::open(filename).context(format!("unable to open '{}'", filename))?;
+    Ok(Box::new(file))
+}
+
+/// Prints how often every word of `files` occurs, for `--freq`.
+fn print_frequencies(
+    freq: &FreqArgs,
+    segmentation: Segmentation,
+    files: &[String],
+) -> anyhow::Result<ExitCode> {
+    let mut options = FreqOptions {
+        fold_case: freq.fold_case,
+        strip_punctuation: freq.strip_punctuation,
+        segmentation,
+        ..FreqOptions::default()
+    };
+    if let Some(ref filename) = freq.stop_words {
+        options
+            .read_stop_words(&mut open(filename)?)
+            .context(format!("unable to read stop words from '{}'", filename))?;
+    }
+
+    let mut frequencies = Frequencies::new(options);
+    let mut failed = 0;
+    for filename in files {
+        let added = open(filename).and_then(|mut input| {
+            frequencies
+                .add(&mut input)
+                .context(format!("unable to count words in '{}'", filename))
+        });
+        if let Err(err) = added {
+            report(&err);
+            failed += 1;
+        }
+    }
+    write_table(
+        &mut io::stdout().lock(),
+        &frequencies.sorted(freq.top),
+        freq.format,
+    )
+    .context("unable to write the word frequencies")?;
+    Ok(exit_code(failed))
+}
+
+/// Reports an input that could not be counted, like `wc` does, before going
+/// on with the next one.
+fn report(err: &anyhow::Error) {
+    eprintln!("rwc: {:#}", err);
+}
+
+/// Like `wc`, fails without another message if `failed` inputs, already
+/// reported, could not be counted.
+fn exit_code(failed: usize) -> ExitCode {
+    if failed > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
 
 /// The run function is called from `main()` in [`rwc`].
 ///
 /// It annotates errors from fallible functions (like `File::open` and
-/// `count_words`) with `.context()` before propagating them upwards, where
-/// `main()` will eventually handle reporting of the error.
-pub fn run() -> anyhow::Result<()> {
-    for filename in env::args().skip(1).collect::<Vec<String>>() {
-        let mut reader = File::open(&filename).context(format!("unable to open '{}'", filename))?;
-        let wordcount =
-            count_words(&mut reader).context(format!("unable to count words in '{}'", filename))?;
-        println!("{} {}", wordcount, filename);
-    }
-    Ok(())
+/// `count`) with `.context()` before propagating them upwards, where
+/// `main()` will eventually handle reporting of the error. An input that
+/// cannot be counted is reported right away instead, so that the others are
+/// still counted and in the total, and the exit code is a failure.
+pub fn run() -> anyhow::Result<ExitCode> {
+    let args = parse_args(env::args().skip(1))?;
+    let (files, named) = inputs(&args)?;
+    if let Some(ref freq) = args.freq {
+        return print_frequencies(freq, args.segmentation, &files);
+    }
+    let width = column_width(&args, &files);
+
+    let mut total = Counts::default();
+    let mut failed = 0;
+    for filename in &files {
+        let counts = open(filename).and_then(|mut input| {
+            count_with(&mut input, args.segmentation)
+                .context(format!("unable to count words in '{}'", filename))
+        });
+        let counts = match counts {
+            Ok(counts) => counts,
+            Err(err) => {
+                report(&err);
+                failed += 1;
+                continue;
+            }
+        };
+        let name = if named { filename.as_str() } else { "" };
+        println!("{}", format_row(&args.fields, &counts, width, name));
+        total += counts;
+    }
+    if files.len() > 1 {
+        println!("{}", format_row(&args.fields, &total, width, "total"));
+    }
+    Ok(exit_code(failed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> anyhow::Result<Args> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_flags_in_any_order() {
+        let parsed = args(&["-Lm", "words.txt", "--lines", "--", "-w"]).unwrap();
+        assert_eq!(
+            parsed.fields,
+            Fields {
+                lines: true,
+                chars: true,
+                max_line_length: true,
+                ..Fields::none()
+            }
+        );
+        assert_eq!(parsed.files, ["words.txt", "-w"]);
+
+        assert_eq!(args(&["words.txt"]).unwrap().fields, Fields::default());
+        assert!(args(&["-x"]).is_err());
+        assert!(args(&["--frobnicate"]).is_err());
+    }
+
+    #[test]
+    fn reads_file_lists() {
+        let parsed = args(&["-l", "--files0-from=names", "-"]);
+        assert!(parsed
+            .unwrap_err()
+            .to_string()
+            .contains("extra operand '-'"));
+        let parsed = args(&["--files0-from", "-", "-w"]).unwrap();
+        assert_eq!(parsed.files0_from.as_deref(), Some("-"));
+        assert!(parsed.files.is_empty());
+
+        let names = parse_files0("words.txt\0a b\0".as_bytes(), "names").unwrap();
+        assert_eq!(names, ["words.txt", "a b"]);
+        assert_eq!(parse_files0("-".as_bytes(), "names").unwrap(), ["-"]);
+        assert!(parse_files0("".as_bytes(), "names").unwrap().is_empty());
+
+        let err = parse_files0("a\0\0b".as_bytes(), "names").unwrap_err();
+        assert_eq!(err.to_string(), "names:2: invalid zero-length file name");
+        assert!(parse_files0("-\0".as_bytes(), "-").is_err());
+    }
+
+    #[test]
+    fn parses_freq_options() {
+        let parsed = args(&["--top", "5", "--freq", "--format=csv", "--fold-case"]).unwrap();
+        assert_eq!(
+            parsed.freq,
+            Some(FreqArgs {
+                fold_case: true,
+                top: Some(5),
+                format: Format::Csv,
+                ..FreqArgs::default()
+            })
+        );
+        assert_eq!(args(&["-l"]).unwrap().freq, None);
+
+        assert!(args(&["--top=5"]).is_err());
+        assert!(args(&["--freq", "--top=many"]).is_err());
+        assert!(args(&["--freq", "--format=xml"]).is_err());
+        assert!(args(&["--freq", "--stop-words"]).is_err());
+        assert!(args(&["--freq=yes"]).is_err());
+
+        let parsed = args(&["--segmentation", "unicode", "-w"]).unwrap();
+        assert_eq!(parsed.segmentation, Segmentation::Unicode);
+        assert!(args(&["--segmentation=icu"]).is_err());
+    }
+
+    #[test]
+    fn formats_rows_like_wc() {
+        let counts = Counts {
+            lines: 2,
+            words: 12,
+            bytes: 70,
+            chars: 65,
+            max_line_length: 33,
+        };
+        assert_eq!(
+            format_row(&Fields::default(), &counts, 3, "a.txt"),
+            "  2  12  70 a.txt"
+        );
+        let fields = Fields {
+            lines: true,
+            ..Fields::none()
+        };
+        assert_eq!(format_row(&fields, &counts, 1, "a.txt"), "2 a.txt");
+    }
 }
@@ -1,13 +1,28 @@
-//! This crate provides the `rwc` (rust word count) binary, named after the `wc
-//! -w` Unix utility.
+//! This crate provides the `rwc` (rust word count) binary, named after the `wc`
+//! Unix utility. Like `wc` it counts lines, words and bytes, or with `-l`,
+//! `-w`, `-c`, `-m` and `-L` any of lines, words, bytes, characters and the
+//! width of the longest line. Without files, or for a file named `-`, it
+//! reads standard input; `--files0-from=F` reads the names of the files from
+//! `F` instead, separated by NUL characters.
+//!
+//! With `--freq` it prints how often every word occurs instead, optionally
+//! with `--fold-case`, `--strip-punctuation`, `--stop-words=FILE`, `--top=N`
+//! and `--format=text|csv|json`. `--segmentation=unicode` splits words at
+//! Unicode word boundaries rather than at whitespace, for text in scripts
+//! that do not put spaces between words.
 //!
 //! This is intended as an example of how I approach error handling in Rust
 //! applications with a focus of different error handling strategies for
 //! application and library code.
 
-fn main() {
-    if let Err(err) = wordcount::run() {
-        eprintln!("Error: {:?}", err);
-        std::process::exit(1);
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    match wordcount::run() {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            ExitCode::FAILURE
+        }
     }
 }
@@ -1,87 +1,365 @@
-use std::error::Error;
-use std::fmt;
-use std::io::{BufRead, BufReader, Read};
+use std::borrow::Cow;
+use std::io::{self, BufRead, BufReader, Read};
+use std::ops::AddAssign;
+use std::str;
 
 use thiserror::Error;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthChar;
 
-// Define enumerates error for word count using anyhow and thiserror
-#[warn(dead_code)]
+/// The ways counting the words of an input can fail.
 #[derive(Error, Debug)]
-pub enum WordCountErrorLib {
+#[non_exhaustive]
+pub enum WordCountError {
     /// Represents an empty source. For example, an empty text file being given
     /// as input to `count_words()`.
     #[error("Source contains no data")]
     EmptySource,
 
-    /// Represents a failure to read from input.
-    #[error("Read error")]
-    ReadError { source: std::io::Error },
+    /// Represents a failure to read from input, while reading line `line`.
+    /// Lines are counted from 1.
+    #[error("Read error on line {line}")]
+    ReadError { line: u64, source: io::Error },
+
+    /// Represents input that is not UTF-8. `offset` is the position of the
+    /// first invalid byte in the whole input, counted from 0.
+    #[error("Invalid UTF-8 on line {line} at byte {offset}")]
+    InvalidUtf8 {
+        line: u64,
+        offset: u64,
+        source: str::Utf8Error,
+    },
 
     /// Represents all other cases of `std::io::Error`.
     #[error(transparent)]
-    IOError(#[from] std::io::Error),
+    IOError(#[from] io::Error),
 }
 
-// WordCountError implement manual
-#[derive(Debug)]
-pub enum WordCountError {
-    EmptySource,
-    ReadError { source: std::io::Error },
-    IOError(std::io::Error),
+/// The counts of one input, in the units `wc` reports them in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counts {
+    /// Newline characters, so a last line without one is not counted.
+    pub lines: u64,
+    /// Words, split as `Segmentation` says.
+    pub words: u64,
+    pub bytes: u64,
+    /// Unicode scalar values.
+    pub chars: u64,
+    /// The display width of the widest line, with tab stops every 8 columns
+    /// and wide characters taking two.
+    pub max_line_length: u64,
 }
 
-impl fmt::Display for WordCountError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            WordCountError::EmptySource => write!(f, "Source contains no data"),
-            WordCountError::IOError(ref err) => err.fmt(f),
-            WordCountError::ReadError { source: ref err } => err.fmt(f),
-        }
+impl AddAssign for Counts {
+    /// Adds up counts for a total. The total's longest line is the longest of
+    /// any input.
+    fn add_assign(&mut self, other: Counts) {
+        self.lines += other.lines;
+        self.words += other.words;
+        self.bytes += other.bytes;
+        self.chars += other.chars;
+        self.max_line_length = self.max_line_length.max(other.max_line_length);
     }
 }
 
-impl Error for WordCountError {
-    fn source(&self) -> Option<&(dyn Error + 'static)> {
-        match *self {
-            WordCountError::EmptySource => None,
-            WordCountError::IOError(ref err) => Some(err),
-            // Our custom error doesn't have an underlying cause,
-            // but we could modify it so that it does.
-            WordCountError::ReadError { source: ref err } => Some(err),
+/// Where one word ends and the next begins.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Segmentation {
+    /// A word is a run of non-whitespace characters, as for `wc`, which
+    /// counts only those starting with a printable character.
+    #[default]
+    Whitespace,
+    /// Words are split at the word boundaries of Unicode Standard Annex #29,
+    /// and only those with a letter or digit in them are counted. The
+    /// standard uses no dictionary, so Chinese and Japanese ideographs, and
+    /// the letters of scripts written without spaces like Thai, are each a
+    /// word of their own.
+    Unicode,
+}
+
+impl Segmentation {
+    /// The words of `line`.
+    pub fn words<'a>(&self, line: &'a str) -> Box<dyn Iterator<Item = &'a str> + 'a> {
+        match self {
+            Segmentation::Whitespace => Box::new(line.split_whitespace()),
+            Segmentation::Unicode => Box::new(line.unicode_words()),
         }
     }
+}
 
-    // fn cause(&self) -> Option<&dyn Error> {
-    //     match *self {
-    //         WordCountError::EmptySource => None,
-    //         WordCountError::IOError(ref err) => Some(err),
-    //         // Our custom error doesn't have an underlying cause,
-    //         // but we could modify it so that it does.
-    //         WordCountError::ReadError { source: ref err } => Some(err),
-    //     }
-    // }
+/// Counts the lines, words, bytes and characters of `input`, and the width
+/// of its widest line. Empty input has all counts 0. Like `wc`, bytes that
+/// are not UTF-8 only count as bytes: they are no characters, take no room
+/// and neither start nor end a word.
+pub fn count<R: Read>(input: &mut R) -> Result<Counts, WordCountError> {
+    count_with(input, Segmentation::Whitespace)
 }
 
-impl From<std::io::Error> for WordCountError {
-    fn from(err: std::io::Error) -> WordCountError {
-        WordCountError::IOError(err)
+/// Like `count()`, but with words split by `segmentation`.
+pub fn count_with<R: Read>(
+    input: &mut R,
+    segmentation: Segmentation,
+) -> Result<Counts, WordCountError> {
+    let mut counts = Counts::default();
+
+    read_byte_lines(input, |line, _, _| {
+        counts.bytes += line.len() as u64;
+        if line.ends_with(b"\n") {
+            counts.lines += 1;
+        }
+        let text = valid_text(line);
+        counts.words += match segmentation {
+            Segmentation::Whitespace => wc_words(&text),
+            Segmentation::Unicode => segmentation.words(&text).count() as u64,
+        };
+        counts.chars += text.chars().count() as u64;
+        counts.max_line_length = counts.max_line_length.max(display_width(&text));
+        Ok(())
+    })?;
+
+    Ok(counts)
+}
+
+/// The words of `text` as `wc` counts them: a word starts at a printable
+/// character after whitespace. Control characters are part of no word.
+fn wc_words(text: &str) -> u64 {
+    let mut words = 0;
+    let mut in_word = false;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            in_word = false;
+        } else if !c.is_control() && !in_word {
+            words += 1;
+            in_word = true;
+        }
     }
+    words
 }
 
-pub fn count_words<R: Read>(input: &mut R) -> Result<i32, WordCountError> {
-    let reader = BufReader::new(input);
-    let mut wordcount = 0;
+/// The text of `line` without any bytes that are not UTF-8.
+fn valid_text(line: &[u8]) -> Cow<'_, str> {
+    match str::from_utf8(line) {
+        Ok(text) => Cow::Borrowed(text),
+        Err(_) => Cow::Owned(line.utf8_chunks().map(|chunk| chunk.valid()).collect()),
+    }
+}
+
+/// Calls `f` with every line of `input` in turn, including its newline if it
+/// has one. The input has to be UTF-8.
+pub(crate) fn read_lines<R, F>(input: &mut R, mut f: F) -> Result<(), WordCountError>
+where
+    R: Read,
+    F: FnMut(&str),
+{
+    read_byte_lines(input, |line, number, offset| {
+        let text = str::from_utf8(line).map_err(|source| WordCountError::InvalidUtf8 {
+            line: number,
+            offset: offset + source.valid_up_to() as u64,
+            source,
+        })?;
+        f(text);
+        Ok(())
+    })
+}
 
-    for line in reader.lines() {
-        let line = line.map_err(|source| WordCountError::ReadError { source })?;
-        for _word in line.split_whitespace() {
-            wordcount += 1;
+/// Calls `f` with every line of `input` in turn, its number counting from 1
+/// and the offset of its first byte in the input, until `f` fails.
+fn read_byte_lines<R, F>(input: &mut R, mut f: F) -> Result<(), WordCountError>
+where
+    R: Read,
+    F: FnMut(&[u8], u64, u64) -> Result<(), WordCountError>,
+{
+    let mut reader = BufReader::new(input);
+    let mut line = Vec::new();
+    let mut number = 0;
+    let mut offset = 0;
+
+    loop {
+        line.clear();
+        number += 1;
+        let read =
+            reader
+                .read_until(b'\n', &mut line)
+                .map_err(|source| WordCountError::ReadError {
+                    line: number,
+                    source,
+                })?;
+        if read == 0 {
+            return Ok(());
         }
+        f(&line, number, offset)?;
+        offset += read as u64;
     }
+}
 
-    if wordcount == 0 {
+/// The widest part of `line` between carriage returns and form feeds, which
+/// like a newline start over at the left margin.
+fn display_width(line: &str) -> u64 {
+    let mut widest = 0;
+    let mut position = 0;
+    for c in line.chars() {
+        match c {
+            '\n' | '\r' | '\x0c' => {
+                widest = widest.max(position);
+                position = 0;
+            }
+            '\t' => position += 8 - position % 8,
+            // Control characters take no room.
+            _ => position += c.width().unwrap_or(0) as u64,
+        }
+    }
+    widest.max(position)
+}
+
+/// Counts the words in `input`, failing if there are none.
+pub fn count_words<R: Read>(input: &mut R) -> Result<u64, WordCountError> {
+    let words = count(input)?.words;
+
+    if words == 0 {
         return Err(WordCountError::EmptySource);
     }
 
-    Ok(wordcount)
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_like_wc() {
+        let text = "Without taking a step outdoors\n\tYou know the whole world;\n東京 café";
+        let counts = count(&mut text.as_bytes()).unwrap();
+
+        assert_eq!(
+            counts,
+            Counts {
+                lines: 2,
+                words: 12,
+                bytes: 70,
+                chars: 65,
+                max_line_length: 33,
+            }
+        );
+    }
+
+    #[test]
+    fn counts_bytes_that_are_not_utf8() {
+        let counts = count(&mut &b"ab\xff\n"[..]).unwrap();
+        assert_eq!((counts.lines, counts.words, counts.bytes), (1, 1, 4));
+
+        // As GNU wc counts them in a UTF-8 locale.
+        let counts = count(&mut &b"ab \xff cd\xffe\n"[..]).unwrap();
+        assert_eq!(
+            counts,
+            Counts {
+                lines: 1,
+                words: 2,
+                bytes: 10,
+                chars: 8,
+                max_line_length: 7,
+            }
+        );
+    }
+
+    #[test]
+    fn leaves_control_characters_out_of_words() {
+        assert_eq!(wc_words("a\0 \u{1} b\n"), 2);
+    }
+
+    #[test]
+    fn measures_display_width() {
+        assert_eq!(display_width("a\tb"), 9);
+        assert_eq!(display_width("東京"), 4);
+        assert_eq!(display_width("long line\rshort"), 9);
+        assert_eq!(display_width("\u{7}bell\n"), 4);
+    }
+
+    #[test]
+    fn empty_input_has_no_words() {
+        assert_eq!(count(&mut "".as_bytes()).unwrap(), Counts::default());
+        assert!(matches!(
+            count_words(&mut " \n".as_bytes()),
+            Err(WordCountError::EmptySource)
+        ));
+    }
+
+    /// Fails once `data` has been read.
+    struct Failing<'a> {
+        data: &'a [u8],
+    }
+
+    impl Read for Failing<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.data.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "gone"));
+            }
+            self.data.read(buf)
+        }
+    }
+
+    #[test]
+    fn reports_where_reading_failed() {
+        let mut input = Failing {
+            data: b"one\ntwo\n",
+        };
+        match count(&mut input) {
+            Err(WordCountError::ReadError { line, ref source }) => {
+                assert_eq!(line, 3);
+                assert_eq!(source.kind(), io::ErrorKind::BrokenPipe);
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+
+        let err = read_lines(&mut &b"caf\xc3\xa9\nna\xefve\n"[..], |_| {}).unwrap_err();
+        assert!(matches!(
+            err,
+            WordCountError::InvalidUtf8 {
+                line: 2,
+                offset: 8,
+                ..
+            }
+        ));
+        assert_eq!(err.to_string(), "Invalid UTF-8 on line 2 at byte 8");
+        assert!(std::error::Error::source(&err).is_some());
+
+        let err = WordCountError::from(io::Error::other("disk on fire"));
+        assert!(matches!(err, WordCountError::IOError(_)));
+        assert_eq!(err.to_string(), "disk on fire");
+        assert_eq!(
+            WordCountError::EmptySource.to_string(),
+            "Source contains no data"
+        );
+    }
+
+    fn unicode_words(text: &str) -> Vec<&str> {
+        Segmentation::Unicode.words(text).collect()
+    }
+
+    #[test]
+    fn segments_words_by_unicode_rules() {
+        // Every ideograph is a word.
+        assert_eq!(unicode_words("我爱北京。"), ["我", "爱", "北", "京"]);
+        // Katakana runs stay together, hiragana and kanji do not.
+        assert_eq!(
+            unicode_words("東京でコーヒーを飲む"),
+            ["東", "京", "で", "コーヒー", "を", "飲", "む"]
+        );
+        // Thai letters split apart, but keep their tone marks.
+        assert_eq!(
+            unicode_words("ไทย ง่าย"),
+            ["ไ", "ท", "ย", "ง\u{e48}", "า", "ย"]
+        );
+        // Apostrophes and decimal points stay inside words, hyphens do not.
+        assert_eq!(
+            unicode_words("Don't over-think 3.14, café—東京!"),
+            ["Don't", "over", "think", "3.14", "café", "東", "京"]
+        );
+
+        let text = "北京 is 北京.\nover-the-top\n";
+        let whitespace = count_with(&mut text.as_bytes(), Segmentation::Whitespace);
+        assert_eq!(whitespace.unwrap().words, 4);
+        let unicode = count_with(&mut text.as_bytes(), Segmentation::Unicode);
+        assert_eq!(unicode.unwrap().words, 8);
+    }
 }
@@ -0,0 +1,88 @@
+// Compares searching a large CSV file sequentially with searching it on
+// several threads. Run with `cargo bench --bench scan`; the number of rows
+// can be given as an argument, e.g. `cargo bench --bench scan -- 2000000`.
+use city_pop::ingest::CsvOptions;
+use city_pop::parallel::Scanner;
+use city_pop::query::Expr;
+use city_pop::search_with;
+use city_pop::select::Selection;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::time::{Duration, Instant};
+use std::{env, process, thread};
+
+const COUNTRIES: [&str; 5] = ["United States", "Brazil", "Germany", "India", "Japan"];
+
+fn write_data(path: &std::path::Path, rows: usize) {
+    let mut out = BufWriter::new(File::create(path).unwrap());
+    writeln!(out, "city,region,country,population,latitude,longitude").unwrap();
+    for i in 0..rows {
+        writeln!(
+            out,
+            "\"City {}, {}\",R{},{},{},{:.4},{:.4}",
+            i % 100_003,
+            i % 7,
+            i % 50,
+            COUNTRIES[i % COUNTRIES.len()],
+            i * 7919 % 1_000_003,
+            (i % 180) as f64 - 90.0,
+            (i % 360) as f64 - 180.0
+        )
+        .unwrap();
+    }
+}
+
+// The best of a few runs, with the number of results.
+fn time<F: FnMut() -> usize>(mut f: F) -> (Duration, usize) {
+    let mut best = Duration::MAX;
+    let mut found = 0;
+    for _ in 0..3 {
+        let start = Instant::now();
+        found = f();
+        best = best.min(start.elapsed());
+    }
+    (best, found)
+}
+
+fn main() {
+    let rows = env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(1_000_000);
+    let path = env::temp_dir().join(format!("city-pop-bench-{}.csv", process::id()));
+    write_data(&path, rows);
+    let size = fs::metadata(&path).unwrap().len();
+
+    let options = CsvOptions::default();
+    let filter = Expr::parse("country=Brazil and population>500000").unwrap();
+    let selection = Selection::default();
+
+    let (sequential, expected) = time(|| {
+        let input = BufReader::new(File::open(&path).unwrap());
+        let mut records = options.records(input).unwrap();
+        search_with(&mut records, &filter, &selection)
+            .unwrap()
+            .rows
+            .len()
+    });
+    println!("{} rows, {:.1} MB", rows, size as f64 / (1 << 20) as f64);
+    println!("sequential: {:>10.1?}", sequential);
+
+    let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+    let mut threads = 2;
+    while threads <= cpus.max(2) {
+        let (parallel, found) = time(|| {
+            let mut scanner = Scanner::new(&path, &options, threads).unwrap();
+            scanner.search(&filter, &selection).unwrap().rows.len()
+        });
+        assert_eq!(found, expected);
+        println!(
+            "{:>2} threads: {:>10.1?}  ({:.2}x)",
+            threads,
+            parallel,
+            sequential.as_secs_f64() / parallel.as_secs_f64()
+        );
+        threads *= 2;
+    }
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,332 @@
+use rusqlite::{Connection, OpenFlags, ToSql};
+use std::collections::HashSet;
+use std::path::Path;
+
+use crate::ingest::{Missing, RecordStream};
+use crate::query::{Cmp, Expr, Field};
+use crate::{fuzzy, CliError, PopulationCount, Record};
+
+// Rows are stored in file order, so that reading them back by `rowid` gives
+// the same results in the same order as scanning the CSV. `city_key` holds
+// the case and accent folded city name for loose lookups. `source_columns`
+// lists which of the optional columns the input had, as a column of NULLs
+// cannot tell a missing column from one without values.
+const SCHEMA: &str = "
+    DROP TABLE IF EXISTS cities;
+    DROP TABLE IF EXISTS source_columns;
+    CREATE TABLE cities (
+        city TEXT NOT NULL,
+        city_key TEXT NOT NULL,
+        region TEXT,
+        country TEXT NOT NULL,
+        population INTEGER,
+        latitude REAL,
+        longitude REAL,
+        year INTEGER
+    );
+    CREATE INDEX cities_city ON cities (city);
+    CREATE INDEX cities_city_key ON cities (city_key);
+    CREATE INDEX cities_country ON cities (country);
+    CREATE TABLE source_columns (name TEXT PRIMARY KEY);
+";
+
+// The columns an input may or may not have.
+const OPTIONAL_COLUMNS: &[&str] = &["region", "latitude", "longitude", "year"];
+
+// Replaces the contents of the database at `db_path` with the records read
+// from `input`, returning how many were imported.
+pub fn import<S: RecordStream, P: AsRef<Path>>(
+    records: &mut S,
+    db_path: P,
+) -> Result<usize, CliError> {
+    let mut conn = Connection::open(db_path)?;
+    import_into(&mut conn, records)
+}
+
+// The matches in the database at `db_path`. Rows without a population are
+// among them, but only count as a match with `Missing::Include`.
+pub fn search<P: AsRef<Path>>(
+    db_path: P,
+    filter: &Expr,
+    missing: Missing,
+) -> Result<Vec<PopulationCount>, CliError> {
+    // Opened read-only so that a mistyped path is an error rather than a
+    // new, empty database.
+    let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    search_in(&conn, filter, missing)
+}
+
+fn import_into<S: RecordStream>(conn: &mut Connection, records: &mut S) -> Result<usize, CliError> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(SCHEMA)?;
+    for column in OPTIONAL_COLUMNS {
+        if records.has_column(column) {
+            tx.execute("INSERT INTO source_columns (name) VALUES (?1)", [column])?;
+        }
+    }
+
+    let mut imported = 0;
+    {
+        let mut insert = tx.prepare(
+            "INSERT INTO cities \
+             (city, city_key, region, country, population, latitude, longitude, year) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        )?;
+        for result in records.by_ref() {
+            let record = result?;
+            insert.execute((
+                &record.city,
+                fuzzy::fold(&record.city),
+                &record.region,
+                &record.country,
+                record.population,
+                record.latitude,
+                record.longitude,
+                record.year,
+            ))?;
+            imported += 1;
+        }
+    }
+    tx.commit()?;
+    Ok(imported)
+}
+
+// Equality terms that every match has to satisfy. These are handed to SQLite
+// so it can use the indexes; the full filter is still applied to each row.
+fn required_equalities<'a>(expr: &'a Expr, terms: &mut Vec<(&'static str, &'a String)>) {
+    match *expr {
+        Expr::Text(Field::City, Cmp::Eq, ref value) => terms.push(("city", value)),
+        Expr::Folded(Field::City, ref value) => terms.push(("city_key", value)),
+        Expr::Text(Field::Region, Cmp::Eq, ref value) => terms.push(("region", value)),
+        Expr::Text(Field::Country, Cmp::Eq, ref value) => terms.push(("country", value)),
+        Expr::And(ref lhs, ref rhs) => {
+            required_equalities(lhs, terms);
+            required_equalities(rhs, terms);
+        }
+        _ => {}
+    }
+}
+
+// The columns of the `cities` table. Databases imported by older versions
+// lack some of them.
+fn table_columns(conn: &Connection) -> Result<HashSet<String>, CliError> {
+    let mut stmt = conn.prepare("SELECT name FROM pragma_table_info('cities')")?;
+    let columns = stmt
+        .query_map((), |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    Ok(columns)
+}
+
+// Whether the input the database was imported from had the optional column
+// `name`. Databases imported before that was recorded can only tell whether
+// any row has a value in it.
+fn had_column(conn: &Connection, columns: &HashSet<String>, name: &str) -> Result<bool, CliError> {
+    let recorded: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master \
+         WHERE type = 'table' AND name = 'source_columns')",
+        (),
+        |row| row.get(0),
+    )?;
+    if recorded {
+        let sql = "SELECT EXISTS (SELECT 1 FROM source_columns WHERE name = ?1)";
+        return Ok(conn.query_row(sql, [name], |row| row.get(0))?);
+    }
+    if !columns.contains(name) {
+        return Ok(false);
+    }
+    let sql = format!(
+        "SELECT EXISTS (SELECT 1 FROM cities WHERE {} IS NOT NULL)",
+        name
+    );
+    Ok(conn.query_row(&sql, (), |row| row.get(0))?)
+}
+
+fn search_in(
+    conn: &Connection,
+    filter: &Expr,
+    missing: Missing,
+) -> Result<Vec<PopulationCount>, CliError> {
+    // The same check `check_columns` makes for a CSV.
+    let columns = table_columns(conn)?;
+    if filter.uses(Field::Region) && !had_column(conn, &columns, "region")? {
+        return Err(CliError::MissingColumn("region".to_string()));
+    }
+
+    let mut terms = vec![];
+    required_equalities(filter, &mut terms);
+
+    // Coordinates are NULL for databases imported before they were stored.
+    let column = |name: &'static str| {
+        if columns.contains(name) {
+            name
+        } else {
+            "NULL"
+        }
+    };
+    let mut sql = format!(
+        "SELECT city, region, country, population, {}, {} FROM cities",
+        column("latitude"),
+        column("longitude")
+    );
+    for (i, (column, _)) in terms.iter().enumerate() {
+        let join = if i == 0 { "WHERE" } else { "AND" };
+        sql.push_str(&format!(" {} {} = ?{}", join, column, i + 1));
+    }
+    sql.push_str(" ORDER BY rowid");
+
+    let params: Vec<&dyn ToSql> = terms
+        .iter()
+        .map(|(_, value)| *value as &dyn ToSql)
+        .collect();
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map(params.as_slice(), |row| {
+        Ok(Record {
+            city: row.get(0)?,
+            region: row.get(1)?,
+            country: row.get(2)?,
+            population: row.get(3)?,
+            latitude: row.get(4)?,
+            longitude: row.get(5)?,
+            // Filters cannot refer to the year, so it is not read back.
+            year: None,
+        })
+    })?;
+
+    let mut found = vec![];
+    for record in rows {
+        let record = record?;
+        if filter.matches(&record) {
+            found.push(PopulationCount::from(record));
+        }
+    }
+
+    let counts = |pop: &PopulationCount| pop.count.is_some() || missing == Missing::Include;
+    if !found.iter().any(counts) {
+        Err(CliError::NotFound(suggest(conn, filter, missing)?))
+    } else {
+        Ok(found)
+    }
+}
+
+// Suggests the cities a search could have counted, as `scan` does for a CSV:
+// those without a population only with `Missing::Include`.
+fn suggest(conn: &Connection, filter: &Expr, missing: Missing) -> Result<Vec<String>, CliError> {
+    let Some(mut suggester) = fuzzy::Suggester::for_filter(filter) else {
+        return Ok(vec![]);
+    };
+    let sql = match missing {
+        Missing::Include => "SELECT DISTINCT city FROM cities",
+        _ => "SELECT DISTINCT city FROM cities WHERE population IS NOT NULL",
+    };
+    let mut stmt = conn.prepare(sql)?;
+    for city in stmt.query_map((), |row| row.get::<_, String>(0))? {
+        suggester.consider(&city?);
+    }
+    Ok(suggester.into_suggestions())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::{CsvOptions, Records};
+
+    const SMALLPOP: &str = include_str!("../smallpop.csv");
+
+    fn records() -> Records<&'static [u8]> {
+        CsvOptions::default().records(SMALLPOP.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn matches_csv_search() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(import_into(&mut conn, &mut records()).unwrap(), 10);
+
+        for query in [
+            "city=Springfield",
+            "city=Springfield and region=OH",
+            "country='United States' and (population>50000 or city~'^North')",
+            "not region=MA",
+        ] {
+            let filter = Expr::parse(query).unwrap();
+            assert_eq!(
+                search_in(&conn, &filter, Missing::Skip).unwrap(),
+                crate::search(&mut records(), &filter).unwrap(),
+                "{}",
+                query
+            );
+        }
+        let filter = Expr::folded(Field::City, "SPRINGFIELD");
+        assert_eq!(
+            search_in(&conn, &filter, Missing::Skip).unwrap(),
+            crate::search(&mut records(), &filter).unwrap()
+        );
+        match search_in(&conn, &Expr::folded(Field::City, "Conkord"), Missing::Skip) {
+            Err(CliError::NotFound(suggestions)) => assert_eq!(suggestions, vec!["Concord"]),
+            _ => panic!("expected no match"),
+        }
+    }
+
+    #[test]
+    fn refuses_region_filter_without_region_column() {
+        let data = "city,country,population\nSpringfield,United States,152227\n";
+        let mut conn = Connection::open_in_memory().unwrap();
+        let mut records = CsvOptions::default().records(data.as_bytes()).unwrap();
+        import_into(&mut conn, &mut records).unwrap();
+
+        let filter = Expr::parse("city=Springfield and region=MA").unwrap();
+        match search_in(&conn, &filter, Missing::Skip) {
+            Err(CliError::MissingColumn(column)) => assert_eq!(column, "region"),
+            result => panic!("unexpected {:?}", result),
+        }
+        let filter = Expr::parse("city=Springfield").unwrap();
+        assert_eq!(search_in(&conn, &filter, Missing::Skip).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn suggests_cities_without_population_when_included() {
+        let data = "city,country,population\nConcord,United States,\n";
+        let mut conn = Connection::open_in_memory().unwrap();
+        let mut records = CsvOptions::default().records(data.as_bytes()).unwrap();
+        import_into(&mut conn, &mut records).unwrap();
+
+        let filter = Expr::folded(Field::City, "Conkord");
+        match search_in(&conn, &filter, Missing::Include) {
+            Err(CliError::NotFound(suggestions)) => assert_eq!(suggestions, vec!["Concord"]),
+            result => panic!("unexpected {:?}", result),
+        }
+        match search_in(&conn, &filter, Missing::Skip) {
+            Err(CliError::NotFound(suggestions)) => assert!(suggestions.is_empty()),
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    #[test]
+    fn reads_databases_without_newer_columns() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE cities (city TEXT NOT NULL, city_key TEXT NOT NULL, \
+             region TEXT, country TEXT NOT NULL, population INTEGER);
+             INSERT INTO cities VALUES ('Concord', 'concord', 'MA', 'United States', 42605);",
+        )
+        .unwrap();
+
+        let filter = Expr::parse("city=Concord and region=MA").unwrap();
+        let found = search_in(&conn, &filter, Missing::Skip).unwrap();
+        assert_eq!(found[0].count, Some(42605));
+        let filter = Expr::parse("region=NH").unwrap();
+        assert!(matches!(
+            search_in(&conn, &filter, Missing::Skip),
+            Err(CliError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn pushes_down_required_equalities() {
+        let filter = Expr::parse("city=A and (country=B or region=C) and region=D").unwrap();
+        let mut terms = vec![];
+        required_equalities(&filter, &mut terms);
+
+        let columns: Vec<_> = terms.iter().map(|(column, _)| *column).collect();
+        assert_eq!(columns, vec!["city", "region"]);
+    }
+}
@@ -0,0 +1,298 @@
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::ingest::RecordStream;
+use crate::output::Row;
+use crate::query::Expr;
+use crate::{check_columns, CliError, Record};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Added => "added",
+            Status::Removed => "removed",
+            Status::Changed => "changed",
+        }
+    }
+
+    fn sign(self) -> char {
+        match self {
+            Status::Added => '+',
+            Status::Removed => '-',
+            Status::Changed => '~',
+        }
+    }
+}
+
+// How a city differs between two snapshots.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Growth {
+    pub status: Status,
+    pub city: String,
+    pub region: Option<String>,
+    pub country: String,
+    pub old: Option<u64>,
+    pub new: Option<u64>,
+    // `new - old`, a missing population counting as 0.
+    pub change: i64,
+    // The change relative to the old population, if there was one.
+    pub percent: Option<f64>,
+}
+
+impl Growth {
+    fn new(status: Status, key: Key, old: Option<u64>, new: Option<u64>) -> Growth {
+        let (city, region, country) = key;
+        let change = new.unwrap_or(0) as i64 - old.unwrap_or(0) as i64;
+        let percent = match (old, new) {
+            (Some(old), Some(_)) if old > 0 => Some(change as f64 * 100.0 / old as f64),
+            _ => None,
+        };
+        Growth {
+            status,
+            city,
+            region,
+            country,
+            old,
+            new,
+            change,
+            percent,
+        }
+    }
+}
+
+impl Row for Growth {
+    const HEADER: &'static [&'static str] = &[
+        "status", "city", "region", "country", "old", "new", "change", "percent",
+    ];
+    const NUMERIC: &'static [&'static str] = &["old", "new", "change", "percent"];
+
+    fn cells(&self) -> Vec<String> {
+        let count = |count: Option<u64>| count.map(|c| c.to_string()).unwrap_or_default();
+        vec![
+            self.status.name().to_string(),
+            self.city.clone(),
+            self.region.clone().unwrap_or_default(),
+            self.country.clone(),
+            count(self.old),
+            count(self.new),
+            format!("{:+}", self.change),
+            self.percent
+                .map(|p| format!("{:+.1}%", p))
+                .unwrap_or_default(),
+        ]
+    }
+
+    fn text(&self) -> String {
+        let mut line = format!("{} {}", self.status.sign(), self.city);
+        if let Some(ref region) = self.region {
+            line.push_str(", ");
+            line.push_str(region);
+        }
+        let count = |count: Option<u64>| count.map_or("?".to_string(), |c| c.to_string());
+        line = format!("{}, {}: ", line, self.country);
+        match self.status {
+            Status::Added => line.push_str(&count(self.new)),
+            Status::Removed => line.push_str(&count(self.old)),
+            Status::Changed => {
+                line.push_str(&format!("{} -> {}", count(self.old), count(self.new)))
+            }
+        }
+        match self.percent {
+            Some(percent) => format!("{} ({:+}, {:+.1}%)", line, self.change, percent),
+            None => format!("{} ({:+})", line, self.change),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortBy {
+    // Added and changed cities in the order of the new snapshot, then the
+    // removed ones in the order of the old.
+    Input,
+    // Largest absolute change first.
+    Change,
+    // Largest absolute percentage first; cities without one go last.
+    Percent,
+    City,
+}
+
+impl SortBy {
+    pub fn parse(name: &str) -> Option<SortBy> {
+        match name {
+            "input" => Some(SortBy::Input),
+            "change" => Some(SortBy::Change),
+            "percent" => Some(SortBy::Percent),
+            "city" => Some(SortBy::City),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Diff {
+    pub growth: Vec<Growth>,
+    // Cities present in both snapshots with the same population.
+    pub unchanged: usize,
+}
+
+impl Diff {
+    pub fn count(&self, status: Status) -> usize {
+        self.growth.iter().filter(|g| g.status == status).count()
+    }
+
+    pub fn sort(&mut self, by: SortBy) {
+        // All sorts are stable, so ties keep the input order.
+        match by {
+            SortBy::Input => {}
+            SortBy::Change => self
+                .growth
+                .sort_by_key(|g| std::cmp::Reverse(g.change.abs())),
+            SortBy::Percent => self.growth.sort_by(|a, b| {
+                let key = |g: &Growth| g.percent.map(f64::abs).unwrap_or(-1.0);
+                key(b).total_cmp(&key(a))
+            }),
+            SortBy::City => self.growth.sort_by(|a, b| {
+                (&a.city, &a.region, &a.country).cmp(&(&b.city, &b.region, &b.country))
+            }),
+        }
+    }
+}
+
+type Key = (String, Option<String>, String);
+
+// Keys in first-seen order and the population of each.
+type Snapshot = (Vec<Key>, HashMap<Key, Option<u64>>);
+
+// The population of every (city, region, country) in first-seen order. If a
+// key repeats, its last row wins.
+fn snapshot<S: RecordStream>(records: &mut S, filter: Option<&Expr>) -> Result<Snapshot, CliError> {
+    if let Some(filter) = filter {
+        check_columns(records, filter)?;
+    }
+    let mut order = vec![];
+    let mut populations = HashMap::new();
+    for result in records.by_ref() {
+        let record = result?;
+        if !filter.is_none_or(|filter| filter.matches(&record)) {
+            continue;
+        }
+        let Record {
+            city,
+            region,
+            country,
+            population,
+            ..
+        } = record;
+        let key = (city, region, country);
+        if populations.insert(key.clone(), population).is_none() {
+            order.push(key);
+        }
+    }
+    Ok((order, populations))
+}
+
+// Joins two snapshots on (city, region, country).
+pub fn diff<R: RecordStream, S: RecordStream>(
+    old: &mut R,
+    new: &mut S,
+    filter: Option<&Expr>,
+) -> Result<Diff, CliError> {
+    let (old_order, mut old) = snapshot(old, filter)?;
+    let (new_order, mut new) = snapshot(new, filter)?;
+    let mut result = Diff::default();
+
+    for key in new_order {
+        let now = new.remove(&key).unwrap_or_default();
+        match old.remove(&key) {
+            None => result
+                .growth
+                .push(Growth::new(Status::Added, key, None, now)),
+            Some(before) if before == now => result.unchanged += 1,
+            Some(before) => result
+                .growth
+                .push(Growth::new(Status::Changed, key, before, now)),
+        }
+    }
+    for key in old_order {
+        if let Some(before) = old.remove(&key) {
+            result
+                .growth
+                .push(Growth::new(Status::Removed, key, before, None));
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::CsvOptions;
+
+    const OLD: &str = "city,region,country,population\n\
+                       Springfield,MA,United States,150000\n\
+                       Springfield,MO,United States,160000\n\
+                       Concord,NH,United States,42605\n\
+                       Northbridge,MA,United States,14061\n";
+    const NEW: &str = "city,region,country,population\n\
+                       Springfield,MO,United States,150443\n\
+                       Springfield,MA,United States,152227\n\
+                       Concord,NH,United States,42605\n\
+                       Westborough,MA,United States,29313\n";
+
+    fn run(filter: Option<&Expr>) -> Diff {
+        let options = CsvOptions::default();
+        diff(
+            &mut options.records(OLD.as_bytes()).unwrap(),
+            &mut options.records(NEW.as_bytes()).unwrap(),
+            filter,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn reports_added_removed_and_changed() {
+        let diff = run(None);
+        let summary: Vec<_> = diff
+            .growth
+            .iter()
+            .map(|g| (g.status, g.city.as_str(), g.change))
+            .collect();
+
+        assert_eq!(
+            summary,
+            vec![
+                (Status::Changed, "Springfield", -9557),
+                (Status::Changed, "Springfield", 2227),
+                (Status::Added, "Westborough", 29313),
+                (Status::Removed, "Northbridge", -14061),
+            ]
+        );
+        assert_eq!(diff.unchanged, 1);
+        assert_eq!(diff.count(Status::Changed), 2);
+        assert!((diff.growth[1].percent.unwrap() - 1.4847).abs() < 1e-3);
+        assert_eq!(diff.growth[2].percent, None);
+        assert_eq!(
+            diff.growth[1].text(),
+            "~ Springfield, MA, United States: 150000 -> 152227 (+2227, +1.5%)"
+        );
+    }
+
+    #[test]
+    fn sorts_by_change() {
+        let mut diff = run(Some(&Expr::parse("region=MA").unwrap()));
+        diff.sort(SortBy::Change);
+        let cities: Vec<_> = diff.growth.iter().map(|g| g.city.as_str()).collect();
+        assert_eq!(cities, vec!["Westborough", "Northbridge", "Springfield"]);
+
+        diff.sort(SortBy::Percent);
+        assert_eq!(diff.growth[0].city, "Springfield");
+    }
+}
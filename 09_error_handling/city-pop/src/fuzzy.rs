@@ -0,0 +1,161 @@
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::query::Expr;
+
+// How many "did you mean" suggestions to offer at most.
+const MAX_SUGGESTIONS: usize = 5;
+
+// Folds a name for loose comparison: compatibility decomposition, then
+// dropping the combining marks (so "São" becomes "Sao") and lowercasing.
+pub fn fold(name: &str) -> String {
+    name.nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// Levenshtein distance between two strings, counted in chars.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut cur = vec![0; b.len() + 1];
+
+    for (i, ca) in a.chars().enumerate() {
+        cur[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(ca != cb);
+            cur[j + 1] = substitution.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    prev[b.len()]
+}
+
+// Collects the names closest to a target while scanning a dataset, so that
+// suggestions can be made without reading the input a second time.
+#[derive(Debug)]
+pub struct Suggester {
+    target: String,
+    // The target as given, if names have to equal it exactly to match.
+    exact: Option<String>,
+    max_distance: usize,
+    // (distance, name), kept sorted and without duplicate names.
+    best: Vec<(usize, String)>,
+    // Whether a name matching the target itself turned up. Nothing is
+    // suggested then: the name is right, and other terms of the filter
+    // ruled its rows out.
+    matched: bool,
+}
+
+impl Suggester {
+    // Suggests names close to `target`, ignoring case and diacritics.
+    pub fn new(target: &str) -> Suggester {
+        let target = fold(target);
+        // Allow roughly one typo for every three characters.
+        let max_distance = (target.chars().count() / 3).max(1);
+        Suggester {
+            target,
+            exact: None,
+            max_distance,
+            best: vec![],
+            matched: false,
+        }
+    }
+
+    // A suggester for the city `filter` requires, if it requires one.
+    pub fn for_filter(filter: &Expr) -> Option<Suggester> {
+        let (city, folded) = filter.required_city()?;
+        let mut suggester = Suggester::new(city);
+        if !folded {
+            suggester.exact = Some(city.to_string());
+        }
+        Some(suggester)
+    }
+
+    pub fn consider(&mut self, name: &str) {
+        let folded = fold(name);
+        let matches = match self.exact {
+            Some(ref exact) => exact == name,
+            None => folded == self.target,
+        };
+        if matches {
+            self.matched = true;
+            return;
+        }
+        let distance = edit_distance(&self.target, &folded);
+        if distance > self.max_distance || self.best.iter().any(|(_, n)| n == name) {
+            return;
+        }
+        let entry = (distance, name.to_string());
+        let pos = self.best.partition_point(|best| *best < entry);
+        if pos < MAX_SUGGESTIONS {
+            self.best.insert(pos, entry);
+            self.best.truncate(MAX_SUGGESTIONS);
+        }
+    }
+
+    // Takes in the names another suggester for the same target saw.
+    pub fn merge(&mut self, other: Suggester) {
+        self.matched |= other.matched;
+        for (_, name) in other.best {
+            self.consider(&name);
+        }
+    }
+
+    pub fn into_suggestions(self) -> Vec<String> {
+        if self.matched {
+            return vec![];
+        }
+        self.best.into_iter().map(|(_, name)| name).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folds_accents_and_case() {
+        assert_eq!(fold("São Paulo"), "sao paulo");
+        assert_eq!(fold("ZÜRICH"), "zurich");
+        assert_eq!(fold("Kraków"), fold("krakow"));
+    }
+
+    #[test]
+    fn measures_edit_distance() {
+        assert_eq!(edit_distance("springfeild", "springfield"), 2);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("concord", "concord"), 0);
+    }
+
+    #[test]
+    fn ranks_suggestions() {
+        let mut suggester = Suggester::new("Springfeild");
+        for name in ["Concord", "Springfield", "Springfeld", "Springfield"] {
+            suggester.consider(name);
+        }
+
+        assert_eq!(
+            suggester.into_suggestions(),
+            vec!["Springfeld", "Springfield"]
+        );
+    }
+
+    #[test]
+    fn suggests_nothing_once_the_name_matches() {
+        let filter = Expr::folded(crate::query::Field::City, "springfield");
+        let mut suggester = Suggester::for_filter(&filter).unwrap();
+        suggester.consider("Springfeld");
+        let mut other = Suggester::for_filter(&filter).unwrap();
+        other.consider("SPRINGFIELD");
+        suggester.merge(other);
+        assert!(suggester.into_suggestions().is_empty());
+
+        // Exact filters want the name as written.
+        let filter = Expr::parse("city=springfield").unwrap();
+        let mut suggester = Suggester::for_filter(&filter).unwrap();
+        suggester.consider("Springfield");
+        assert_eq!(suggester.into_suggestions(), vec!["Springfield"]);
+    }
+}
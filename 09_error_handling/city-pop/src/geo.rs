@@ -0,0 +1,318 @@
+use serde::Serialize;
+
+use crate::ingest::RecordStream;
+use crate::output::Row;
+use crate::query::Expr;
+use crate::{check_columns, CliError};
+
+// Mean radius of the Earth.
+const EARTH_RADIUS_KM: f64 = 6371.0088;
+
+// Great-circle distance between two (latitude, longitude) points in degrees.
+pub fn haversine_km(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (lat1, lat2) = (from.0.to_radians(), to.0.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (to.1 - from.1).to_radians();
+    let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().min(1.0).asin()
+}
+
+// Parses a distance like `50km`, `500m` or `30mi` into kilometres. A bare
+// number is taken to be in kilometres.
+pub fn parse_radius(arg: &str) -> Result<f64, CliError> {
+    let arg = arg.trim();
+    let (number, scale) = if let Some(number) = arg.strip_suffix("km") {
+        (number, 1.0)
+    } else if let Some(number) = arg.strip_suffix("mi") {
+        (number, 1.609344)
+    } else if let Some(number) = arg.strip_suffix('m') {
+        (number, 0.001)
+    } else {
+        (arg, 1.0)
+    };
+    match number.trim().parse::<f64>() {
+        Ok(radius) if radius >= 0.0 && radius.is_finite() => Ok(radius * scale),
+        _ => Err(CliError::Args(format!("invalid radius '{}'", arg))),
+    }
+}
+
+pub fn parse_coordinates(lat: &str, lon: &str) -> Result<(f64, f64), CliError> {
+    match (lat.parse::<f64>(), lon.parse::<f64>()) {
+        (Ok(lat), Ok(lon)) if valid(lat, lon) => Ok((lat, lon)),
+        _ => Err(CliError::Args(format!(
+            "invalid coordinates '{} {}', expected a latitude and a longitude in degrees",
+            lat, lon
+        ))),
+    }
+}
+
+fn valid(lat: f64, lon: f64) -> bool {
+    (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon)
+}
+
+// Position on the unit sphere. Straight-line (chord) distances between these
+// grow with great-circle distance, so a plain 3-d tree can answer radius
+// queries without special cases at the poles or the antimeridian.
+fn unit_vector((lat, lon): (f64, f64)) -> [f64; 3] {
+    let (lat, lon) = (lat.to_radians(), lon.to_radians());
+    [lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin()]
+}
+
+fn squared_distance(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum()
+}
+
+// A k-d tree stored in a single vector: every slice is split at its middle
+// element on the axis given by its depth, smaller values to the left.
+struct KdTree {
+    points: Vec<([f64; 3], usize)>,
+}
+
+impl KdTree {
+    fn new(mut points: Vec<([f64; 3], usize)>) -> KdTree {
+        KdTree::build(&mut points, 0);
+        KdTree { points }
+    }
+
+    fn build(points: &mut [([f64; 3], usize)], depth: usize) {
+        if points.len() <= 1 {
+            return;
+        }
+        let mid = points.len() / 2;
+        let axis = depth % 3;
+        points.select_nth_unstable_by(mid, |a, b| a.0[axis].total_cmp(&b.0[axis]));
+        let (left, right) = points.split_at_mut(mid);
+        KdTree::build(left, depth + 1);
+        KdTree::build(&mut right[1..], depth + 1);
+    }
+
+    // Indices of all points within `radius` of `target`.
+    fn within(&self, target: &[f64; 3], radius: f64) -> Vec<usize> {
+        let mut found = vec![];
+        KdTree::search(&self.points, 0, target, radius, &mut found);
+        found
+    }
+
+    fn search(
+        points: &[([f64; 3], usize)],
+        depth: usize,
+        target: &[f64; 3],
+        radius: f64,
+        found: &mut Vec<usize>,
+    ) {
+        if points.is_empty() {
+            return;
+        }
+        let mid = points.len() / 2;
+        let (point, index) = &points[mid];
+        if squared_distance(point, target) <= radius * radius {
+            found.push(*index);
+        }
+        let axis = depth % 3;
+        if target[axis] - radius <= point[axis] {
+            KdTree::search(&points[..mid], depth + 1, target, radius, found);
+        }
+        if target[axis] + radius >= point[axis] {
+            KdTree::search(&points[mid + 1..], depth + 1, target, radius, found);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Nearby {
+    pub city: String,
+    pub region: Option<String>,
+    pub country: String,
+    pub population: u64,
+    pub distance_km: f64,
+}
+
+impl Row for Nearby {
+    const HEADER: &'static [&'static str] =
+        &["city", "region", "country", "population", "distance_km"];
+    const NUMERIC: &'static [&'static str] = &["population", "distance_km"];
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.city.clone(),
+            self.region.clone().unwrap_or_default(),
+            self.country.clone(),
+            self.population.to_string(),
+            format!("{:.1}", self.distance_km),
+        ]
+    }
+
+    fn text(&self) -> String {
+        let mut line = self.city.clone();
+        if let Some(ref region) = self.region {
+            line.push_str(", ");
+            line.push_str(region);
+        }
+        format!(
+            "{}, {}: {} ({:.1} km)",
+            line, self.country, self.population, self.distance_km
+        )
+    }
+}
+
+// The cities of a dataset that have coordinates and a population, indexed
+// for radius queries.
+pub struct GeoIndex {
+    // (city, position) in input order.
+    cities: Vec<(Nearby, (f64, f64))>,
+    tree: KdTree,
+}
+
+impl GeoIndex {
+    pub fn build<S: RecordStream>(
+        records: &mut S,
+        filter: Option<&Expr>,
+    ) -> Result<GeoIndex, CliError> {
+        for column in ["latitude", "longitude"] {
+            if !records.has_column(column) {
+                return Err(CliError::MissingColumn(column.to_string()));
+            }
+        }
+        if let Some(filter) = filter {
+            check_columns(records, filter)?;
+        }
+
+        let mut cities = vec![];
+        for result in records.by_ref() {
+            let record = result?;
+            if !filter.is_none_or(|filter| filter.matches(&record)) {
+                continue;
+            }
+            if let (Some(population), Some(lat), Some(lon)) =
+                (record.population, record.latitude, record.longitude)
+            {
+                if !valid(lat, lon) {
+                    continue;
+                }
+                let city = Nearby {
+                    city: record.city,
+                    region: record.region,
+                    country: record.country,
+                    population,
+                    distance_km: 0.0,
+                };
+                cities.push((city, (lat, lon)));
+            }
+        }
+
+        let points = cities
+            .iter()
+            .enumerate()
+            .map(|(i, (_, position))| (unit_vector(*position), i))
+            .collect();
+        Ok(GeoIndex {
+            cities,
+            tree: KdTree::new(points),
+        })
+    }
+
+    // Cities within `radius_km` of `center`, closest first.
+    pub fn near(&self, center: (f64, f64), radius_km: f64) -> Vec<Nearby> {
+        // The chord subtending an arc of `radius_km` on the unit sphere.
+        let angle = (radius_km / EARTH_RADIUS_KM).min(std::f64::consts::PI);
+        let chord = 2.0 * (angle / 2.0).sin();
+
+        let mut found: Vec<(f64, usize)> = self
+            .tree
+            .within(&unit_vector(center), chord * (1.0 + 1e-9))
+            .into_iter()
+            .map(|i| (haversine_km(center, self.cities[i].1), i))
+            .filter(|(distance, _)| *distance <= radius_km)
+            .collect();
+        found.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+        found
+            .into_iter()
+            .map(|(distance, i)| Nearby {
+                distance_km: distance,
+                ..self.cities[i].0.clone()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::CsvOptions;
+
+    const CITIES: &str = "city,country,population,lat,lon\n\
+                          Paris,France,2102650,48.8566,2.3522\n\
+                          Versailles,France,83587,48.8049,2.1204\n\
+                          London,United Kingdom,8866180,51.5072,-0.1276\n\
+                          Suva,Fiji,93970,-18.1416,178.4419\n\
+                          Taveuni,Fiji,,-16.8,-179.97\n\
+                          Apia,Samoa,37708,-13.8333,-171.75\n";
+
+    fn index() -> GeoIndex {
+        let mut records = CsvOptions::default().records(CITIES.as_bytes()).unwrap();
+        GeoIndex::build(&mut records, None).unwrap()
+    }
+
+    #[test]
+    fn measures_great_circle_distance() {
+        let distance = haversine_km((48.8566, 2.3522), (51.5072, -0.1276));
+        assert!((distance - 343.9).abs() < 1.0, "{}", distance);
+        assert_eq!(parse_radius("30mi").unwrap(), 30.0 * 1.609344);
+        assert_eq!(parse_radius("500m").unwrap(), 0.5);
+        assert!(parse_radius("far").is_err());
+    }
+
+    #[test]
+    fn finds_cities_within_radius() {
+        let index = index();
+        assert_eq!(index.cities.len(), 5);
+
+        let near_paris: Vec<_> = index
+            .near((48.85, 2.35), 50.0)
+            .into_iter()
+            .map(|city| city.city)
+            .collect();
+        assert_eq!(near_paris, vec!["Paris", "Versailles"]);
+
+        // Across the antimeridian, from just east of it.
+        let near_taveuni: Vec<_> = index
+            .near((-16.8, -179.97), 1200.0)
+            .into_iter()
+            .map(|city| city.city)
+            .collect();
+        assert_eq!(near_taveuni, vec!["Suva", "Apia"]);
+    }
+
+    #[test]
+    fn agrees_with_brute_force() {
+        let mut csv = String::from("city,country,population,latitude,longitude\n");
+        let mut seed = 12345u64;
+        let mut next = || {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 11) as f64 / (1u64 << 53) as f64
+        };
+        for i in 0..500 {
+            let lat = next() * 180.0 - 90.0;
+            let lon = next() * 360.0 - 180.0;
+            csv.push_str(&format!("c{},X,1,{},{}\n", i, lat, lon));
+        }
+        let mut records = CsvOptions::default().records(csv.as_bytes()).unwrap();
+        let index = GeoIndex::build(&mut records, None).unwrap();
+
+        for &(center, radius) in &[
+            ((0.0, 0.0), 2000.0),
+            ((89.0, 10.0), 1500.0),
+            ((10.0, 179.0), 3000.0),
+        ] {
+            let expected = index
+                .cities
+                .iter()
+                .filter(|(_, position)| haversine_km(center, *position) <= radius)
+                .count();
+            assert_eq!(index.near(center, radius).len(), expected, "{:?}", center);
+        }
+    }
+}
@@ -0,0 +1,262 @@
+// The population of cities over time, for datasets with one row per city
+// and year.
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::fuzzy::Suggester;
+use crate::ingest::RecordStream;
+use crate::output::Row;
+use crate::query::Expr;
+use crate::{check_columns, CliError};
+
+// Sparkline characters from the lowest population to the highest.
+const LEVELS: &[u8] = b"_.-:=+*#";
+
+// A city's population in one year.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct YearCount {
+    pub city: String,
+    pub region: Option<String>,
+    pub country: String,
+    pub year: i32,
+    pub population: u64,
+}
+
+impl Row for YearCount {
+    const HEADER: &'static [&'static str] = &["city", "region", "country", "year", "population"];
+    const NUMERIC: &'static [&'static str] = &["year", "population"];
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.city.clone(),
+            self.region.clone().unwrap_or_default(),
+            self.country.clone(),
+            self.year.to_string(),
+            self.population.to_string(),
+        ]
+    }
+
+    fn text(&self) -> String {
+        match self.region {
+            Some(ref region) => format!(
+                "{}, {}, {} in {}: {}",
+                self.city, region, self.country, self.year, self.population
+            ),
+            None => format!(
+                "{}, {} in {}: {}",
+                self.city, self.country, self.year, self.population
+            ),
+        }
+    }
+}
+
+// The populations of one city, by year.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub city: String,
+    pub region: Option<String>,
+    pub country: String,
+    // Ordered by year, one population per year.
+    pub points: Vec<(i32, u64)>,
+}
+
+impl Series {
+    // The compound annual growth rate from the first year to the last, as a
+    // fraction. There is none for a single year or a first population of 0.
+    pub fn cagr(&self) -> Option<f64> {
+        let (&(first_year, first), &(last_year, last)) =
+            (self.points.first()?, self.points.last()?);
+        if last_year <= first_year || first == 0 {
+            return None;
+        }
+        let years = f64::from(last_year - first_year);
+        Some((last as f64 / first as f64).powf(1.0 / years) - 1.0)
+    }
+
+    // One character per year in the series, higher for larger populations.
+    // Gaps between years are not shown.
+    pub fn sparkline(&self) -> String {
+        let counts = self.points.iter().map(|&(_, count)| count);
+        let (Some(min), Some(max)) = (counts.clone().min(), counts.clone().max()) else {
+            return String::new();
+        };
+        let top = (LEVELS.len() - 1) as u64;
+        counts
+            .map(|count| match max - min {
+                0 => LEVELS[LEVELS.len() / 2],
+                range => LEVELS[(((count - min) * top + range / 2) / range) as usize],
+            } as char)
+            .collect()
+    }
+
+    pub fn rows(&self) -> Vec<YearCount> {
+        self.points
+            .iter()
+            .map(|&(year, population)| YearCount {
+                city: self.city.clone(),
+                region: self.region.clone(),
+                country: self.country.clone(),
+                year,
+                population,
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Series {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.city)?;
+        if let Some(ref region) = self.region {
+            write!(f, ", {}", region)?;
+        }
+        writeln!(f, ", {}", self.country)?;
+
+        let width = self
+            .points
+            .iter()
+            .map(|(_, count)| count.to_string().len())
+            .max()
+            .unwrap_or(0);
+        for (year, count) in &self.points {
+            writeln!(f, "  {:>4}  {:>width$}", year, count, width = width)?;
+        }
+        if let Some(cagr) = self.cagr() {
+            let (first, last) = (self.points[0].0, self.points[self.points.len() - 1].0);
+            writeln!(
+                f,
+                "  CAGR {:+.2}% a year from {} to {}",
+                cagr * 100.0,
+                first,
+                last
+            )?;
+        }
+        writeln!(f, "  {}", self.sparkline())
+    }
+}
+
+type Key = (String, Option<String>, String);
+
+// The series of every (city, region, country) matching `filter`, in the
+// order they first appear. Rows without a year or a population are left out;
+// if a year repeats for a city, its last row wins.
+pub fn history<S: RecordStream>(records: &mut S, filter: &Expr) -> Result<Vec<Series>, CliError> {
+    check_columns(records, filter)?;
+    if !records.has_column("year") {
+        return Err(CliError::MissingColumn("year".to_string()));
+    }
+    let mut suggester = Suggester::for_filter(filter);
+
+    let mut order: Vec<Key> = vec![];
+    let mut years: HashMap<Key, HashMap<i32, u64>> = HashMap::new();
+    for result in records.by_ref() {
+        let record = result?;
+        let (Some(year), Some(count)) = (record.year, record.population) else {
+            continue;
+        };
+        // Suggestions are only needed if nothing matches at all.
+        if let (Some(suggester), true) = (suggester.as_mut(), order.is_empty()) {
+            suggester.consider(&record.city);
+        }
+        if !filter.matches(&record) {
+            continue;
+        }
+        let key = (record.city, record.region, record.country);
+        if !years.contains_key(&key) {
+            order.push(key.clone());
+        }
+        years.entry(key).or_default().insert(year, count);
+    }
+
+    if order.is_empty() {
+        return Err(CliError::NotFound(
+            suggester.map_or(vec![], Suggester::into_suggestions),
+        ));
+    }
+    Ok(order
+        .into_iter()
+        .map(|key| {
+            let mut points: Vec<(i32, u64)> = years.remove(&key).unwrap().into_iter().collect();
+            points.sort_unstable();
+            let (city, region, country) = key;
+            Series {
+                city,
+                region,
+                country,
+                points,
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::CsvOptions;
+    use crate::query::Field;
+
+    const DATA: &str = "\
+city,region,country,population,year
+Springfield,MA,United States,153060,2010
+Springfield,MO,United States,159498,2010
+Springfield,MA,United States,156983,1990
+Springfield,MA,United States,,2000
+Springfield,MA,United States,155929,2020
+Springfield,MA,United States,152082,2000
+Springfield,MA,United States,155000,2020
+";
+
+    fn series(filter: &Expr) -> Result<Vec<Series>, CliError> {
+        let mut records = CsvOptions::default().records(DATA.as_bytes()).unwrap();
+        history(&mut records, filter)
+    }
+
+    #[test]
+    fn builds_series_in_year_order() {
+        let filter = Expr::and(
+            Some(Expr::folded(Field::City, "springfield")),
+            Some(Expr::parse("region=MA").unwrap()),
+        )
+        .unwrap();
+        let found = series(&filter).unwrap();
+
+        assert_eq!(found.len(), 1);
+        assert_eq!(
+            found[0].points,
+            vec![
+                (1990, 156983),
+                (2000, 152082),
+                (2010, 153060),
+                (2020, 155000)
+            ]
+        );
+        let cagr = found[0].cagr().unwrap();
+        assert!((cagr - -0.000424).abs() < 1e-6, "{}", cagr);
+        assert_eq!(found[0].sparkline(), "#_.=");
+        assert_eq!(found[0].rows()[3].population, 155000);
+        assert!(found[0]
+            .to_string()
+            .contains("CAGR -0.04% a year from 1990 to 2020"));
+    }
+
+    #[test]
+    fn handles_short_series_and_misses() {
+        let found = series(&Expr::parse("region=MO").unwrap()).unwrap();
+        assert_eq!(found[0].cagr(), None);
+        assert_eq!(found[0].sparkline(), "=");
+
+        match series(&Expr::folded(Field::City, "Springfeld")) {
+            Err(CliError::NotFound(suggestions)) => assert_eq!(suggestions, ["Springfield"]),
+            result => panic!("unexpected {:?}", result),
+        }
+
+        let mut records = CsvOptions::default()
+            .records("city,country,population\nConcord,United States,42605\n".as_bytes())
+            .unwrap();
+        let filter = Expr::folded(Field::City, "Concord");
+        match history(&mut records, &filter) {
+            Err(CliError::MissingColumn(column)) => assert_eq!(column, "year"),
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::{io, slice};
+
+use crate::fuzzy;
+use crate::ingest::{CsvOptions, RecordStream};
+use crate::query::{Expr, Field};
+use crate::stats::{self, GroupBy, Summary};
+use crate::{CliError, PopulationCount, Record};
+
+// A dataset of cities loaded into memory, for programs that query it more
+// than once, such as `city-pop shell`.
+#[derive(Debug, Clone, Default)]
+pub struct CityIndex {
+    records: Vec<Record>,
+    has_region: bool,
+}
+
+impl CityIndex {
+    // Loads a comma separated dataset with a header row.
+    pub fn from_reader<R: io::Read>(input: R) -> Result<CityIndex, CliError> {
+        CityIndex::from_csv(input, &CsvOptions::default())
+    }
+
+    // Loads a dataset read according to `options`. In lenient mode rows that
+    // cannot be read are left out.
+    pub fn from_csv<R: io::Read>(input: R, options: &CsvOptions) -> Result<CityIndex, CliError> {
+        CityIndex::read(&mut options.records(input)?)
+    }
+
+    // Reads the rest of `records`. Their diagnostics stay available to the
+    // caller afterwards.
+    pub fn read<S: RecordStream>(records: &mut S) -> Result<CityIndex, CliError> {
+        let has_region = records.has_column("region");
+        let records = records.by_ref().collect::<Result<_, _>>()?;
+        Ok(CityIndex {
+            records,
+            has_region,
+        })
+    }
+
+    // Wraps records that were read some other way. Region filters are
+    // allowed if any of them has a region.
+    pub fn from_records(records: Vec<Record>) -> CityIndex {
+        let has_region = records.iter().any(|record| record.region.is_some());
+        CityIndex {
+            records,
+            has_region,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    // All records, in input order.
+    pub fn iter(&self) -> slice::Iter<'_, Record> {
+        self.records.iter()
+    }
+
+    // Records for the city called `name`, ignoring case and accents.
+    pub fn find<'a>(&'a self, name: &str) -> impl Iterator<Item = &'a Record> + 'a {
+        let key = fuzzy::fold(name);
+        self.records
+            .iter()
+            .filter(move |record| fuzzy::fold(&record.city) == key)
+    }
+
+    pub fn in_country<'a>(&'a self, country: &'a str) -> impl Iterator<Item = &'a Record> + 'a {
+        self.records
+            .iter()
+            .filter(move |record| record.country == country)
+    }
+
+    pub fn in_region<'a>(&'a self, region: &'a str) -> impl Iterator<Item = &'a Record> + 'a {
+        self.records
+            .iter()
+            .filter(move |record| record.region.as_deref() == Some(region))
+    }
+
+    // Records matching a query, see `Expr::parse`.
+    pub fn filter<'a>(&'a self, filter: &'a Expr) -> impl Iterator<Item = &'a Record> + 'a {
+        self.records
+            .iter()
+            .filter(move |record| filter.matches(record))
+    }
+
+    // The same results the crate's `search` gives for this dataset: every
+    // match with a population, or `CliError::NotFound` with suggestions.
+    pub fn search(&self, filter: &Expr) -> Result<Vec<PopulationCount>, CliError> {
+        if filter.uses(Field::Region) && !self.has_region {
+            return Err(CliError::MissingColumn("region".to_string()));
+        }
+
+        let found: Vec<_> = self
+            .filter(filter)
+            .filter_map(PopulationCount::of)
+            .collect();
+        if !found.is_empty() {
+            return Ok(found);
+        }
+
+        let mut suggestions = vec![];
+        if let Some(mut suggester) = fuzzy::Suggester::for_filter(filter) {
+            for record in self.records.iter().filter(|r| r.population.is_some()) {
+                suggester.consider(&record.city);
+            }
+            suggestions = suggester.into_suggestions();
+        }
+        Err(CliError::NotFound(suggestions))
+    }
+
+    // The `n` most populous cities, largest first. Ties keep input order.
+    pub fn largest(&self, n: usize) -> Vec<PopulationCount> {
+        let mut counts: Vec<_> = self
+            .records
+            .iter()
+            .filter_map(PopulationCount::of)
+            .collect();
+        counts.sort_by_key(|pop| std::cmp::Reverse(pop.count));
+        counts.truncate(n);
+        counts
+    }
+
+    // The combined population of all records that have one.
+    pub fn total_population(&self) -> u64 {
+        self.records
+            .iter()
+            .filter_map(|record| record.population)
+            .sum()
+    }
+
+    // Per-country or per-region statistics, as printed by `city-pop stats`.
+    // Grouping or filtering by region needs a region column, as in `search`.
+    pub fn summarize(
+        &self,
+        filter: Option<&Expr>,
+        by: GroupBy,
+        top: usize,
+    ) -> Result<Summary, CliError> {
+        let by_region = by == GroupBy::Region || filter.is_some_and(|f| f.uses(Field::Region));
+        if by_region && !self.has_region {
+            return Err(CliError::MissingColumn("region".to_string()));
+        }
+        let records = self.records.iter().cloned().map(Ok);
+        stats::summarize_records(records, filter, by, top)
+    }
+}
+
+impl<'a> IntoIterator for &'a CityIndex {
+    type Item = &'a Record;
+    type IntoIter = slice::Iter<'a, Record>;
+
+    fn into_iter(self) -> slice::Iter<'a, Record> {
+        self.records.iter()
+    }
+}
@@ -0,0 +1,607 @@
+use csv::StringRecord;
+use serde::{de, Deserialize, Deserializer};
+use std::path::Path;
+use std::{fmt, io};
+
+use crate::objects::ObjectRecords;
+use crate::{CliError, Record};
+
+// The columns `Record` is deserialised from.
+const COLUMNS: [&str; 7] = [
+    "city",
+    "region",
+    "country",
+    "population",
+    "latitude",
+    "longitude",
+    "year",
+];
+
+// The columns assumed when the input has no header row and no `--map`.
+const DEFAULT_ORDER: [&str; 4] = ["city", "region", "country", "population"];
+
+// Common abbreviations accepted as header names.
+const ALIASES: [(&str, &str); 4] = [
+    ("lat", "latitude"),
+    ("lon", "longitude"),
+    ("lng", "longitude"),
+    ("long", "longitude"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Csv,
+    // A single JSON array of objects.
+    Json,
+    // One JSON object per line.
+    Ndjson,
+    Parquet,
+}
+
+impl InputFormat {
+    pub fn parse(name: &str) -> Option<InputFormat> {
+        match name {
+            "csv" => Some(InputFormat::Csv),
+            "json" => Some(InputFormat::Json),
+            "ndjson" | "jsonl" => Some(InputFormat::Ndjson),
+            "parquet" => Some(InputFormat::Parquet),
+            _ => None,
+        }
+    }
+
+    // Guesses the format from a file extension. Anything unknown is read as
+    // CSV, which also covers `.tsv` and `.txt` with a `--delimiter`.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> InputFormat {
+        let extension = path
+            .as_ref()
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_lowercase);
+        match extension.as_deref() {
+            Some("json") => InputFormat::Json,
+            Some("ndjson") | Some("jsonl") => InputFormat::Ndjson,
+            Some("parquet") | Some("pq") => InputFormat::Parquet,
+            _ => InputFormat::Csv,
+        }
+    }
+}
+
+// What to do with rows that have no population.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Missing {
+    // Leave them out of results, as if they did not match.
+    #[default]
+    Skip,
+    // Show them, with an unknown population.
+    Include,
+    // Treat them as invalid rows, like a malformed population.
+    Error,
+}
+
+impl Missing {
+    pub fn parse(name: &str) -> Option<Missing> {
+        match name {
+            "skip" => Some(Missing::Skip),
+            "include" => Some(Missing::Include),
+            "error" => Some(Missing::Error),
+            _ => None,
+        }
+    }
+}
+
+// How to read a CSV: its delimiter, whether it has a header row, and which of
+// its columns hold the fields of a `Record`.
+#[derive(Debug, Clone)]
+pub struct CsvOptions {
+    pub delimiter: u8,
+    pub has_headers: bool,
+    // (column, source) pairs. With a header row the source is a header name,
+    // e.g. `("population", "Population (2020)")`; without one it is a
+    // 1-based column number.
+    pub mapping: Vec<(String, String)>,
+    // Skip rows that cannot be read, collecting a `Diagnostic` for each,
+    // instead of failing on the first one.
+    pub lenient: bool,
+    // With `Missing::Error`, a row without a population cannot be read.
+    pub missing: Missing,
+}
+
+impl Default for CsvOptions {
+    fn default() -> CsvOptions {
+        CsvOptions {
+            delimiter: b',',
+            has_headers: true,
+            mapping: vec![],
+            lenient: false,
+            missing: Missing::Skip,
+        }
+    }
+}
+
+impl CsvOptions {
+    // Parses a `--delimiter` argument. `\t` and `tab` mean a tab.
+    pub fn parse_delimiter(arg: &str) -> Result<u8, CliError> {
+        match arg {
+            "\\t" | "tab" => Ok(b'\t'),
+            _ if arg.len() == 1 && arg.is_ascii() => Ok(arg.as_bytes()[0]),
+            _ => Err(CliError::Args(format!(
+                "the delimiter must be a single ASCII character, not '{}'",
+                arg
+            ))),
+        }
+    }
+
+    // Parses a `--map column=source` argument.
+    pub fn parse_mapping(arg: &str) -> Result<(String, String), CliError> {
+        match arg.split_once('=') {
+            Some((column, source)) if COLUMNS.contains(&column.trim()) => {
+                Ok((column.trim().to_string(), source.to_string()))
+            }
+            _ => Err(CliError::Args(format!(
+                "expected --map <column>=<source> with column one of {}, not '{}'",
+                COLUMNS.join(", "),
+                arg
+            ))),
+        }
+    }
+
+    // Reads `input` in `format`. The delimiter and header options only apply
+    // to CSV; the mapping of columns and leniency apply to all formats.
+    pub fn open<R: io::Read + 'static>(
+        &self,
+        input: R,
+        format: InputFormat,
+    ) -> Result<Box<dyn RecordStream>, CliError> {
+        Ok(match format {
+            InputFormat::Csv => Box::new(self.records(input)?),
+            InputFormat::Json => Box::new(ObjectRecords::from_json(input, self)?),
+            InputFormat::Ndjson => Box::new(ObjectRecords::from_ndjson(input, self)?),
+            InputFormat::Parquet => Box::new(ObjectRecords::from_parquet(input, self)?),
+        })
+    }
+
+    pub fn records<R: io::Read>(&self, input: R) -> Result<Records<R>, CliError> {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(self.delimiter)
+            .has_headers(self.has_headers)
+            .from_reader(input);
+
+        let headers = if self.has_headers {
+            self.rename_headers(reader.headers()?)?
+        } else {
+            self.number_columns()?
+        };
+        require_columns(|column| headers.iter().any(|header| header == column))?;
+
+        Ok(Records {
+            reader,
+            population: headers.iter().position(|header| header == "population"),
+            headers,
+            record: StringRecord::new(),
+            lenient: self.lenient,
+            missing: self.missing,
+            diagnostics: vec![],
+            start: Start::default(),
+        })
+    }
+
+    // Reads a part of a CSV whose header row, if any, has been read already
+    // and gave `headers`. `start` says where in the whole file `input`
+    // begins, so that diagnostics point at the right place.
+    pub(crate) fn chunk<R: io::Read>(
+        &self,
+        input: R,
+        headers: &StringRecord,
+        start: Start,
+    ) -> Records<R> {
+        let reader = csv::ReaderBuilder::new()
+            .delimiter(self.delimiter)
+            .has_headers(false)
+            // Field counts are checked against `start.fields` instead, as
+            // the first row of a chunk says nothing about the others.
+            .flexible(true)
+            .from_reader(input);
+        Records {
+            reader,
+            population: headers.iter().position(|header| header == "population"),
+            headers: headers.clone(),
+            record: StringRecord::new(),
+            lenient: self.lenient,
+            missing: self.missing,
+            diagnostics: vec![],
+            start,
+        }
+    }
+
+    // Gives each source column the name of the `Record` field it holds, see
+    // `field_for`. Every source named in `mapping` has to exist.
+    fn rename_headers(&self, headers: &StringRecord) -> Result<StringRecord, CliError> {
+        for (_, source) in &self.mapping {
+            if !headers.iter().any(|header| header.trim() == source.trim()) {
+                return Err(CliError::MissingColumn(source.clone()));
+            }
+        }
+        let renamed: Vec<String> = headers
+            .iter()
+            .map(|header| self.field_for(header).unwrap_or_default())
+            .collect();
+        Ok(StringRecord::from(renamed))
+    }
+
+    // The `Record` field a column named `name` holds, if any. Names matching
+    // a field up to case and surrounding whitespace, or one of the `ALIASES`,
+    // are accepted as they are; `mapping` can point a field at any other
+    // column, and whatever column previously claimed that field gives it up.
+    pub(crate) fn field_for(&self, name: &str) -> Option<String> {
+        if let Some((column, _)) = self
+            .mapping
+            .iter()
+            .find(|(_, source)| source.trim() == name.trim())
+        {
+            return Some(column.clone());
+        }
+
+        let name = name.trim().to_lowercase();
+        let field = match ALIASES.iter().find(|(alias, _)| *alias == name) {
+            Some((_, column)) => column.to_string(),
+            None if COLUMNS.contains(&name.as_str()) => name,
+            None => return None,
+        };
+        match self.mapping.iter().any(|(column, _)| *column == field) {
+            true => None,
+            false => Some(field),
+        }
+    }
+
+    fn number_columns(&self) -> Result<StringRecord, CliError> {
+        if self.mapping.is_empty() {
+            return Ok(StringRecord::from(DEFAULT_ORDER.to_vec()));
+        }
+
+        let mut names = vec![];
+        for (column, source) in &self.mapping {
+            let index = match source.trim().parse::<usize>() {
+                Ok(number) if number > 0 => number - 1,
+                _ => {
+                    return Err(CliError::Args(format!(
+                        "without a header row, --map needs a column number, not '{}'",
+                        source
+                    )))
+                }
+            };
+            if names.len() <= index {
+                names.resize(index + 1, String::new());
+            }
+            names[index] = column.clone();
+        }
+        Ok(StringRecord::from(names))
+    }
+}
+
+// Fails unless the input has every column a `Record` cannot do without.
+pub(crate) fn require_columns<F: Fn(&str) -> bool>(has_column: F) -> Result<(), CliError> {
+    for column in ["city", "country", "population"] {
+        if !has_column(column) {
+            return Err(CliError::MissingColumn(column.to_string()));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    // The row has a different number of fields than the ones before it.
+    FieldCount { expected: u64, found: u64 },
+    InvalidUtf8,
+    InvalidPopulation,
+    // The row has no population, which `Missing::Error` does not allow.
+    MissingPopulation,
+    // Any other reason the row could not be turned into a `Record`.
+    Malformed(String),
+}
+
+impl Problem {
+    // A short name to group problems by in reports.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Problem::FieldCount { .. } => "field-count",
+            Problem::InvalidUtf8 => "invalid-utf8",
+            Problem::InvalidPopulation => "invalid-population",
+            Problem::MissingPopulation => "missing-population",
+            Problem::Malformed(_) => "malformed",
+        }
+    }
+}
+
+// Why a row of the input could not be read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    // The line of the row. JSON arrays and Parquet files have no useful line
+    // numbers, so there it is the 1-based number of the record instead.
+    pub line: u64,
+    pub byte: u64,
+    pub column: Option<String>,
+    // The offending value, where it is valid UTF-8.
+    pub value: Option<String>,
+    pub problem: Problem,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, byte {}: ", self.line, self.byte)?;
+        let column = self.column.as_deref().unwrap_or("?");
+        match self.problem {
+            Problem::FieldCount { expected, found } => {
+                write!(f, "found {} fields, expected {}", found, expected)
+            }
+            Problem::InvalidUtf8 => write!(f, "invalid UTF-8 in column '{}'", column),
+            Problem::InvalidPopulation => write!(
+                f,
+                "invalid population '{}'",
+                self.value.as_deref().unwrap_or_default()
+            ),
+            Problem::MissingPopulation => write!(f, "missing population"),
+            Problem::Malformed(ref reason) => write!(f, "column '{}': {}", column, reason),
+        }
+    }
+}
+
+// The records of an input in any of the supported formats, in input order.
+// In lenient mode rows that cannot be read are skipped and collected as
+// diagnostics; otherwise the first one ends the stream with an error.
+pub trait RecordStream: Iterator<Item = Result<Record, CliError>> {
+    // Whether the input has a column for the `Record` field `name`.
+    fn has_column(&self, name: &str) -> bool;
+
+    // The rows skipped so far in lenient mode.
+    fn diagnostics(&self) -> &[Diagnostic];
+}
+
+impl<S: RecordStream + ?Sized> RecordStream for Box<S> {
+    fn has_column(&self, name: &str) -> bool {
+        (**self).has_column(name)
+    }
+
+    fn diagnostics(&self) -> &[Diagnostic] {
+        (**self).diagnostics()
+    }
+}
+
+// An iterator over the records of a CSV, read with `CsvOptions`.
+pub struct Records<R> {
+    reader: csv::Reader<R>,
+    headers: StringRecord,
+    population: Option<usize>,
+    record: StringRecord,
+    lenient: bool,
+    missing: Missing,
+    diagnostics: Vec<Diagnostic>,
+    start: Start,
+}
+
+// Where the input of a `Records` starts within a larger CSV.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Start {
+    // Lines and bytes before the input.
+    pub lines: u64,
+    pub bytes: u64,
+    // The number of fields every row must have, if the input is a chunk.
+    pub fields: Option<u64>,
+}
+
+impl<R: io::Read> RecordStream for Records<R> {
+    fn has_column(&self, name: &str) -> bool {
+        self.headers.iter().any(|header| header == name)
+    }
+
+    fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+}
+
+impl<R: io::Read> Records<R> {
+    fn column_name(&self, index: Option<u64>) -> Option<String> {
+        let index = index? as usize;
+        Some(match self.headers.get(index) {
+            Some(name) if !name.is_empty() => name.to_string(),
+            _ => format!("#{}", index + 1),
+        })
+    }
+
+    // The headers, renamed to the `Record` fields they hold.
+    pub(crate) fn headers(&self) -> &StringRecord {
+        &self.headers
+    }
+
+    // Where the next row starts.
+    pub(crate) fn position(&self) -> &csv::Position {
+        self.reader.position()
+    }
+
+    fn diagnostic(&self, pos: Option<&csv::Position>, problem: Problem) -> Diagnostic {
+        Diagnostic {
+            line: pos.map_or(0, |pos| self.start.lines + pos.line()),
+            byte: pos.map_or(0, |pos| self.start.bytes + pos.byte()),
+            column: None,
+            value: None,
+            problem,
+        }
+    }
+
+    // Turns errors about the shape or encoding of a row into diagnostics.
+    // Anything else, like an I/O error, is not the row's fault.
+    fn diagnose(&self, err: csv::Error) -> CliError {
+        let (problem, column) = match *err.kind() {
+            csv::ErrorKind::UnequalLengths {
+                expected_len, len, ..
+            } => (
+                Problem::FieldCount {
+                    expected: expected_len,
+                    found: len,
+                },
+                None,
+            ),
+            csv::ErrorKind::Utf8 { ref err, .. } => (
+                Problem::InvalidUtf8,
+                self.column_name(Some(err.field() as u64)),
+            ),
+            _ => return CliError::Csv(err),
+        };
+        let mut diagnostic = self.diagnostic(err.position(), problem);
+        diagnostic.column = column;
+        CliError::InvalidRow(diagnostic)
+    }
+
+    fn parse(&self) -> Result<Record, Diagnostic> {
+        let pos = self.record.position();
+        if let Some(index) = self.population {
+            let raw = self.record.get(index).unwrap_or_default();
+            let problem = match parse_population(raw) {
+                Err(_) => Some(Problem::InvalidPopulation),
+                Ok(None) if self.missing == Missing::Error => Some(Problem::MissingPopulation),
+                Ok(_) => None,
+            };
+            if let Some(problem) = problem {
+                let mut diagnostic = self.diagnostic(pos, problem);
+                diagnostic.column = Some("population".to_string());
+                diagnostic.value = Some(raw.to_string());
+                return Err(diagnostic);
+            }
+        }
+
+        self.record
+            .deserialize(Some(&self.headers))
+            .map_err(|err| match *err.kind() {
+                csv::ErrorKind::Deserialize { ref err, .. } => {
+                    let mut diagnostic =
+                        self.diagnostic(pos, Problem::Malformed(err.kind().to_string()));
+                    diagnostic.column = self.column_name(err.field());
+                    diagnostic.value = err
+                        .field()
+                        .and_then(|i| self.record.get(i as usize))
+                        .map(String::from);
+                    diagnostic
+                }
+                _ => self.diagnostic(pos, Problem::Malformed(err.to_string())),
+            })
+    }
+
+    fn read_row(&mut self) -> Option<Result<Record, CliError>> {
+        match self.reader.read_record(&mut self.record) {
+            Ok(true) => match self.start.fields {
+                Some(expected) if self.record.len() as u64 != expected => {
+                    let problem = Problem::FieldCount {
+                        expected,
+                        found: self.record.len() as u64,
+                    };
+                    let diagnostic = self.diagnostic(self.record.position(), problem);
+                    Some(Err(CliError::InvalidRow(diagnostic)))
+                }
+                _ => Some(self.parse().map_err(CliError::InvalidRow)),
+            },
+            Ok(false) => None,
+            Err(err) => Some(Err(self.diagnose(err))),
+        }
+    }
+}
+
+impl<R: io::Read> Iterator for Records<R> {
+    type Item = Result<Record, CliError>;
+
+    fn next(&mut self) -> Option<Result<Record, CliError>> {
+        loop {
+            match self.read_row()? {
+                Err(CliError::InvalidRow(diagnostic)) if self.lenient => {
+                    self.diagnostics.push(diagnostic)
+                }
+                result => return Some(result),
+            }
+        }
+    }
+}
+
+// Deserialises a population, tolerating digit group separators as in
+// `1,234,567` or `1 234 567`. An empty field is a missing population.
+pub fn population<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(raw) => parse_population(&raw)
+            .map_err(|_| de::Error::custom(format!("invalid population '{}'", raw))),
+        None => Ok(None),
+    }
+}
+
+pub fn parse_population(raw: &str) -> Result<Option<u64>, std::num::ParseIntError> {
+    let digits: String = raw
+        .trim()
+        .chars()
+        .filter(|c| !matches!(c, ',' | '_' | '\'' | ' ' | '\u{a0}' | '\u{202f}'))
+        .collect();
+    if digits.is_empty() {
+        return Ok(None);
+    }
+    digits.parse().map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(options: &CsvOptions, data: &str) -> Vec<Record> {
+        options
+            .records(data.as_bytes())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn maps_renamed_columns() {
+        let options = CsvOptions {
+            delimiter: b';',
+            mapping: vec![CsvOptions::parse_mapping("population=Population (2020)").unwrap()],
+            ..CsvOptions::default()
+        };
+        let data = "City;Country;Population (2010);Population (2020)\n\
+                    Springfield;United States;\"150,000\";\"152,227\"\n";
+
+        let records = read(&options, data);
+        assert_eq!(records[0].city, "Springfield");
+        assert_eq!(records[0].population, Some(152227));
+    }
+
+    #[test]
+    fn reads_tsv_without_header() {
+        let options = CsvOptions {
+            delimiter: CsvOptions::parse_delimiter("\\t").unwrap(),
+            has_headers: false,
+            mapping: vec![
+                ("country".to_string(), "1".to_string()),
+                ("city".to_string(), "2".to_string()),
+                ("population".to_string(), "4".to_string()),
+            ],
+            ..CsvOptions::default()
+        };
+        let data = "Brazil\tSão Paulo\tSP\t12 325 232\nBrazil\tBrasília\tDF\t\n";
+
+        let records = read(&options, data);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].city, "São Paulo");
+        assert_eq!(records[0].region, None);
+        assert_eq!(records[0].population, Some(12325232));
+        assert_eq!(records[1].population, None);
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(CsvOptions::parse_delimiter("::").is_err());
+        assert!(CsvOptions::parse_mapping("size=Population").is_err());
+        assert!(matches!(
+            CsvOptions {
+                mapping: vec![("city".to_string(), "Town".to_string())],
+                ..CsvOptions::default()
+            }
+            .records("City,Country\n".as_bytes()),
+            Err(CliError::MissingColumn(_))
+        ));
+    }
+}
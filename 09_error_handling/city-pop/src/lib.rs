@@ -0,0 +1,433 @@
+//! Population lookups over datasets of cities in CSV, JSON, NDJSON or
+//! Parquet files.
+//!
+//! [`CityIndex`] loads a dataset into memory and answers typed queries about
+//! it. The modules below hold the pieces the `city-pop` binary is built
+//! from: the query language, CSV ingestion, output formats, statistics,
+//! radius search and the SQLite store. Every input format is read as an
+//! [`ingest::RecordStream`], from a file, a gzip file or an HTTP download
+//! ([`source::DataSource`]).
+
+pub mod db;
+pub mod diff;
+pub mod fuzzy;
+pub mod geo;
+pub mod history;
+mod index;
+pub mod ingest;
+mod objects;
+pub mod output;
+pub mod parallel;
+pub mod query;
+pub mod select;
+pub mod serve;
+pub mod shell;
+pub mod source;
+pub mod stats;
+pub mod validate;
+
+pub use index::CityIndex;
+
+use ingest::{Missing, RecordStream};
+use query::{Expr, Field};
+use select::{Found, Selection};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::{fmt, io};
+
+// This struct represents the data in each row of the CSV file.
+// Type based decoding absolves us of a lot of the nitty-gritty error
+// handling, like parsing strings as integers or floats.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Record {
+    pub country: String,
+    pub city: String,
+    // Not every dataset has a region column; serde leaves it as `None` then.
+    #[serde(default)]
+    pub region: Option<String>,
+    #[serde(deserialize_with = "ingest::population")]
+    pub population: Option<u64>,
+    // Coordinates in decimal degrees, for datasets that have them.
+    #[serde(default)]
+    pub latitude: Option<f64>,
+    #[serde(default)]
+    pub longitude: Option<f64>,
+    // The year the population was counted in, for datasets with one row
+    // per city and year.
+    #[serde(default)]
+    pub year: Option<i32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PopulationCount {
+    pub city: String,
+    pub region: Option<String>,
+    pub country: String,
+    // `None` only for rows without a population that were asked for with
+    // `--missing include`.
+    #[serde(rename = "population")]
+    pub count: Option<u64>,
+}
+
+impl PopulationCount {
+    // The count for `record`, if it has a population.
+    pub fn of(record: &Record) -> Option<PopulationCount> {
+        record.population?;
+        Some(PopulationCount::from(record.clone()))
+    }
+}
+
+impl From<Record> for PopulationCount {
+    fn from(record: Record) -> PopulationCount {
+        PopulationCount {
+            city: record.city,
+            region: record.region,
+            country: record.country,
+            count: record.population,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CliError {
+    // Invalid command line arguments.
+    Args(String),
+    IoError(io::Error),
+    Csv(csv::Error),
+    Sqlite(rusqlite::Error),
+    // A JSON array input is not valid JSON.
+    Json(serde_json::Error),
+    Parquet(parquet::errors::ParquetError),
+    Query(query::ParseError),
+    // A row of the input could not be read.
+    InvalidRow(ingest::Diagnostic),
+    // `validate` found this many rows that could not be read.
+    InvalidRows(usize),
+    // The filter refers to a column that the input does not have.
+    MissingColumn(String),
+    // Nothing matched. Carries similarly named cities, if any.
+    NotFound(Vec<String>),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CliError::Args(ref msg) => msg.fmt(f),
+            CliError::IoError(ref err) => err.fmt(f),
+            CliError::Csv(ref err) => err.fmt(f),
+            CliError::Sqlite(ref err) => err.fmt(f),
+            CliError::Json(ref err) => write!(f, "invalid JSON: {}", err),
+            CliError::Parquet(ref err) => err.fmt(f),
+            CliError::Query(ref err) => err.fmt(f),
+            CliError::InvalidRow(ref diagnostic) => diagnostic.fmt(f),
+            CliError::InvalidRows(count) => write!(f, "{} rows failed validation.", count),
+            CliError::MissingColumn(ref name) => {
+                write!(f, "The input has no '{}' column.", name)
+            }
+            CliError::NotFound(ref suggestions) => {
+                write!(f, "No matching cities with a population were found.")?;
+                if !suggestions.is_empty() {
+                    write!(f, " Did you mean: {}?", suggestions.join(", "))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for CliError {
+    fn cause(&self) -> Option<&dyn Error> {
+        match *self {
+            CliError::Args(_) => None,
+            CliError::IoError(ref err) => Some(err),
+            CliError::Csv(ref err) => Some(err),
+            CliError::Sqlite(ref err) => Some(err),
+            CliError::Json(ref err) => Some(err),
+            CliError::Parquet(ref err) => Some(err),
+            CliError::Query(ref err) => Some(err),
+            CliError::InvalidRow(_)
+            | CliError::InvalidRows(_)
+            | CliError::MissingColumn(_)
+            | CliError::NotFound(_) => None,
+        }
+    }
+}
+
+impl CliError {
+    // The process exit status `city-pop` uses for this kind of error.
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            CliError::NotFound(_) => 1,
+            CliError::Args(_) | CliError::Query(_) => 2,
+            CliError::IoError(_) | CliError::Sqlite(_) => 3,
+            CliError::Csv(ref err) if err.is_io_error() => 3,
+            CliError::Json(ref err) if err.is_io() => 3,
+            CliError::Csv(_)
+            | CliError::Json(_)
+            | CliError::Parquet(_)
+            | CliError::InvalidRow(_)
+            | CliError::InvalidRows(_)
+            | CliError::MissingColumn(_) => 4,
+        }
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(err: io::Error) -> CliError {
+        CliError::IoError(err)
+    }
+}
+
+impl From<csv::Error> for CliError {
+    fn from(err: csv::Error) -> CliError {
+        CliError::Csv(err)
+    }
+}
+
+impl From<rusqlite::Error> for CliError {
+    fn from(err: rusqlite::Error) -> CliError {
+        CliError::Sqlite(err)
+    }
+}
+
+impl From<query::ParseError> for CliError {
+    fn from(err: query::ParseError) -> CliError {
+        CliError::Query(err)
+    }
+}
+
+// Older datasets have no region column, in which case every record's region is
+// `None`. Filtering on it would silently match nothing, so refuse instead.
+pub fn check_columns<S: RecordStream>(records: &S, filter: &Expr) -> Result<(), CliError> {
+    if filter.uses(Field::Region) && !records.has_column("region") {
+        return Err(CliError::MissingColumn("region".to_string()));
+    }
+    Ok(())
+}
+
+pub fn search<S: RecordStream>(
+    records: &mut S,
+    filter: &Expr,
+) -> Result<Vec<PopulationCount>, CliError> {
+    search_with(records, filter, &Selection::default()).map(|found| found.rows)
+}
+
+// Like `search`, but only returns the `selection` of the matches. Without a
+// sort order the input is read no further than the last row selected.
+pub fn search_with<S: RecordStream>(
+    records: &mut S,
+    filter: &Expr,
+    selection: &Selection,
+) -> Result<Found, CliError> {
+    match scan(records, filter, selection)? {
+        Scan::Found(found) => Ok(found),
+        Scan::NotFound(suggester) => Err(CliError::NotFound(
+            suggester.map_or(vec![], fuzzy::Suggester::into_suggestions),
+        )),
+    }
+}
+
+// The outcome of a search that could be read to the end.
+pub(crate) enum Scan {
+    Found(Found),
+    // Nothing matched. Holds the suggester for the city the filter requires,
+    // so that the suggestions of several scans can be merged.
+    NotFound(Option<fuzzy::Suggester>),
+}
+
+// `search_with`, without turning a miss into an error.
+pub(crate) fn scan<S: RecordStream>(
+    records: &mut S,
+    filter: &Expr,
+    selection: &Selection,
+) -> Result<Scan, CliError> {
+    let mut found = selection.collector();
+    let mut suggester = fuzzy::Suggester::for_filter(filter);
+
+    check_columns(records, filter)?;
+
+    while !found.is_full() {
+        let Some(result) = records.next() else {
+            break;
+        };
+        let record = result?;
+
+        // Suggestions are only needed if nothing matches at all.
+        if let (Some(suggester), 0) = (suggester.as_mut(), found.seen()) {
+            if record.population.is_some() || selection.missing == Missing::Include {
+                suggester.consider(&record.city);
+            }
+        }
+        // The collector leaves out rows without a population, unless
+        // `selection` includes them.
+        if filter.matches(&record) {
+            found.push(PopulationCount::from(record));
+        }
+    }
+
+    if found.seen() == 0 {
+        Ok(Scan::NotFound(suggester))
+    } else {
+        Ok(Scan::Found(found.finish()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::{CsvOptions, Records};
+    use crate::query::Cmp;
+
+    const SMALLPOP: &str = include_str!("../smallpop.csv");
+
+    fn records(data: &str) -> Records<&[u8]> {
+        CsvOptions::default().records(data.as_bytes()).unwrap()
+    }
+
+    fn city(name: &str) -> Expr {
+        Expr::Text(Field::City, Cmp::Eq, name.to_string())
+    }
+
+    #[test]
+    fn suggests_similar_cities() {
+        let data = "city,country,population\nSão Paulo,Brazil,12325232\n";
+
+        assert_eq!(
+            search(&mut records(data), &Expr::folded(Field::City, "SAO PAULO"))
+                .unwrap()
+                .len(),
+            1
+        );
+        match search(
+            &mut records(SMALLPOP),
+            &Expr::folded(Field::City, "Springfeild"),
+        ) {
+            Err(CliError::NotFound(suggestions)) => assert_eq!(suggestions, vec!["Springfield"]),
+            _ => panic!("expected no match"),
+        }
+
+        // The name is right, the region rules every row out.
+        let filter = Expr::and(
+            Some(Expr::folded(Field::City, "springfield")),
+            Some(Expr::parse("region=ZZ").unwrap()),
+        )
+        .unwrap();
+        match search(&mut records(SMALLPOP), &filter) {
+            Err(CliError::NotFound(suggestions)) => assert!(suggestions.is_empty()),
+            _ => panic!("expected no match"),
+        }
+    }
+
+    #[test]
+    fn keeps_region_of_each_city() {
+        let found = search(&mut records(SMALLPOP), &city("Springfield")).unwrap();
+        let regions: Vec<_> = found.iter().map(|pop| pop.region.as_deref()).collect();
+
+        assert_eq!(
+            regions,
+            vec![Some("MA"), Some("MO"), Some("NJ"), Some("OH"), Some("OR")]
+        );
+    }
+
+    #[test]
+    fn region_column_is_optional() {
+        let data = "city,country,population\nSpringfield,United States,152227\n";
+        let found = search(&mut records(data), &city("Springfield")).unwrap();
+
+        assert_eq!(found[0].region, None);
+        assert!(matches!(
+            search(&mut records(data), &Expr::parse("region=MA").unwrap()),
+            Err(CliError::MissingColumn(ref column)) if column == "region"
+        ));
+    }
+
+    #[test]
+    fn reports_csv_position() {
+        let data = "city,country,population\nSpringfield,United States,many\n";
+        let err = search(&mut records(data), &city("Springfield")).unwrap_err();
+
+        assert_eq!(err.exit_code(), 4);
+        assert_eq!(
+            err.to_string(),
+            "line 2, byte 24: invalid population 'many'"
+        );
+    }
+
+    #[test]
+    fn stops_reading_once_a_page_is_full() {
+        let data = "city,country,population\n\
+                    Springfield,United States,152227\n\
+                    Springfield,United States,150443\n\
+                    Springfield,United States,many\n";
+        let first = Selection {
+            limit: Some(1),
+            offset: 1,
+            ..Selection::default()
+        };
+        let found = search_with(&mut records(data), &city("Springfield"), &first).unwrap();
+        assert_eq!(found.rows[0].count, Some(150443));
+
+        // Sorting has to look at every row, and so runs into the bad one.
+        let sorted = Selection {
+            sort: Some(select::SortKey::Population),
+            ..first
+        };
+        assert!(search_with(&mut records(data), &city("Springfield"), &sorted).is_err());
+    }
+
+    #[test]
+    fn applies_missing_population_policy() {
+        let data = "city,country,population\n\
+                    Springfield,United States,\n\
+                    Springfield,United States,152227\n\
+                    Concord,United States,\n";
+        let skip = search_with(
+            &mut records(data),
+            &city("Springfield"),
+            &Selection::default(),
+        );
+        let skip = skip.unwrap();
+        assert_eq!((skip.rows.len(), skip.missing, skip.partial), (1, 1, false));
+
+        // The page is full before the second row without a population.
+        let first = Selection {
+            limit: Some(1),
+            ..Selection::default()
+        };
+        let found = search_with(
+            &mut records(data),
+            &Expr::parse("population>0 or country=\"United States\"").unwrap(),
+            &first,
+        )
+        .unwrap();
+        assert_eq!((found.missing, found.partial), (1, true));
+
+        let include = Selection {
+            missing: Missing::Include,
+            ..Selection::default()
+        };
+        let found = search_with(&mut records(data), &city("Springfield"), &include).unwrap();
+        assert_eq!(found.rows[0].count, None);
+        assert_eq!(found.missing, 1);
+        assert_eq!(output::count(found.rows[0].count), "unknown");
+        // A city with only rows without a population is found, too.
+        assert!(search_with(&mut records(data), &city("Concord"), &include).is_ok());
+        assert!(search_with(&mut records(data), &city("Concord"), &Selection::default()).is_err());
+
+        let options = CsvOptions {
+            missing: Missing::Error,
+            ..CsvOptions::default()
+        };
+        let mut strict = options.records(data.as_bytes()).unwrap();
+        match search_with(&mut strict, &city("Springfield"), &Selection::default()) {
+            Err(CliError::InvalidRow(diagnostic)) => {
+                assert_eq!(diagnostic.problem, ingest::Problem::MissingPopulation);
+                assert_eq!(
+                    diagnostic.to_string(),
+                    "line 2, byte 24: missing population"
+                );
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+}
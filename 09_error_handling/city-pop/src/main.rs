@@ -1,111 +1,399 @@
 extern crate getopts;
 
+use city_pop::ingest::{CsvOptions, Diagnostic, InputFormat, Missing, RecordStream};
+use city_pop::output::{self, Format};
+use city_pop::parallel::Scanner;
+use city_pop::query::{Cmp, Expr, Field};
+use city_pop::select::{Found, Selection, SortKey};
+use city_pop::serve::Server;
+use city_pop::source::{self, DataSource};
+use city_pop::stats::{self, GroupBy};
+use city_pop::{db, diff, geo, history, search_with, shell, validate, CityIndex, CliError};
 use getopts::Options;
-use serde::Deserialize;
-use std::error::Error;
-use std::fs::File;
-use std::{env, fmt, io, path::Path, process};
-
-// This struct represents the data in each row of the CSV file.
-// Type based decoding absolves us of a lot of the nitty-gritty error
-// handling, like parsing strings as integers or floats.
-#[derive(Debug, Deserialize)]
-struct Record {
-    country: String,
-    city: String,
-    population: Option<u64>,
-}
+use std::io::{self, Write};
+use std::{env, process, thread};
 
-struct PopulationCount {
-    city: String,
-    country: String,
-    // This is no longer an `Option` because values of this type are only
-    // constructed if they have a population count.
-    count: u64,
+fn print_usage(program: &str, opts: &Options) {
+    println!(
+        "{}",
+        opts.usage(&format!(
+            "Usage: {0} [options] [<city>]\n       {0} stats [options]\n       \
+             {0} import [<data-path>] --db <db-path>\n       \
+             {0} validate [options]\n       \
+             {0} near [options] [--] <latitude> <longitude>\n       \
+             {0} serve [options] --port <port>\n       \
+             {0} diff [options] <old-path> <new-path>\n       \
+             {0} history [options] <city>\n       \
+             {0} shell [options]\n\n\
+             Exit status is 1 if nothing matched, 2 for invalid arguments or\n\
+             queries, 3 for I/O errors and 4 for malformed input data.",
+            program
+        ))
+    );
 }
 
-#[derive(Debug)]
-enum CliError {
-    IoError(io::Error),
-    Csv(csv::Error),
-    NotFound,
+// `-f` takes a path or a URL-like source; without it, standard input is read.
+fn data_source(file_path: Option<&String>) -> Result<Option<Box<dyn DataSource>>, CliError> {
+    file_path.map(|spec| source::parse(spec)).transpose()
 }
 
-impl fmt::Display for CliError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            CliError::IoError(ref err) => err.fmt(f),
-            CliError::Csv(ref err) => err.fmt(f),
-            CliError::NotFound => write!(f, "No matching cities with a population were found."),
-        }
+fn missing(matches: &getopts::Matches) -> Result<Missing, CliError> {
+    match matches.opt_str("missing") {
+        None => Ok(Missing::Skip),
+        Some(name) => Missing::parse(&name).ok_or_else(|| {
+            CliError::Args(format!(
+                "unknown --missing policy '{}', use skip, include or error",
+                name
+            ))
+        }),
     }
 }
 
-impl Error for CliError {
-    fn cause(&self) -> Option<&dyn Error> {
-        match *self {
-            CliError::IoError(ref err) => Some(err),
-            CliError::Csv(ref err) => Some(err),
-            CliError::NotFound => None,
-        }
+fn csv_options(matches: &getopts::Matches) -> Result<CsvOptions, CliError> {
+    let mut options = CsvOptions {
+        has_headers: !matches.opt_present("no-header"),
+        // `validate` always reads on to the end to report every bad row.
+        lenient: matches.opt_present("lenient")
+            || matches.free.first().is_some_and(|cmd| cmd == "validate"),
+        missing: missing(matches)?,
+        ..CsvOptions::default()
+    };
+    if let Some(delimiter) = matches.opt_str("delimiter") {
+        options.delimiter = CsvOptions::parse_delimiter(&delimiter)?;
+    }
+    for mapping in matches.opt_strs("map") {
+        options.mapping.push(CsvOptions::parse_mapping(&mapping)?);
     }
+    Ok(options)
 }
 
-impl From<io::Error> for CliError {
-    fn from(err: io::Error) -> CliError {
-        CliError::IoError(err)
+// The format is taken from `--input-format`, else from the file extension.
+// Standard input is read as CSV unless told otherwise.
+fn input_format(
+    matches: &getopts::Matches,
+    source: Option<&dyn DataSource>,
+) -> Result<InputFormat, CliError> {
+    match matches.opt_str("input-format") {
+        Some(name) => InputFormat::parse(&name)
+            .ok_or_else(|| CliError::Args(format!("unknown input format '{}'", name))),
+        None => Ok(source.map_or(InputFormat::Csv, |source| {
+            InputFormat::from_path(source.path())
+        })),
     }
 }
 
-impl From<csv::Error> for CliError {
-    fn from(err: csv::Error) -> CliError {
-        CliError::Csv(err)
+fn open_records(
+    matches: &getopts::Matches,
+    file_path: Option<&String>,
+) -> Result<Box<dyn RecordStream>, CliError> {
+    read_source(matches, data_source(file_path)?.as_deref())
+}
+
+fn read_source(
+    matches: &getopts::Matches,
+    source: Option<&dyn DataSource>,
+) -> Result<Box<dyn RecordStream>, CliError> {
+    let format = input_format(matches, source)?;
+    let input = match source {
+        Some(source) => source.open()?,
+        None => Box::new(io::stdin()),
+    };
+    csv_options(matches)?.open(input, format)
+}
+
+// In lenient mode, tells the user about the rows that were skipped.
+fn report_skipped(matches: &getopts::Matches, skipped: &[Diagnostic]) {
+    if skipped.is_empty() || matches.opt_present("q") {
+        return;
+    }
+    eprintln!("Skipped {} invalid rows:", skipped.len());
+    for diagnostic in skipped {
+        eprintln!("  {}", diagnostic);
     }
 }
 
-fn print_usage(program: &str, opts: Options) {
-    println!(
-        "{}",
-        opts.usage(&format!("Usage: {} [options] <data-path> <city>", program))
-    );
+// Builds the filter from the positional city (unless a subcommand was given),
+// `--region` and every `--where` expression.
+fn build_filter(
+    matches: &getopts::Matches,
+    city: Option<&String>,
+) -> Result<Option<Expr>, CliError> {
+    // The positional city matches regardless of case and accents, unless
+    // `--exact` asks for the same byte-for-byte comparison as `city=<city>`.
+    let mut filter = city.map(|city| match matches.opt_present("exact") {
+        true => Expr::Text(Field::City, Cmp::Eq, city.clone()),
+        false => Expr::folded(Field::City, city),
+    });
+    if let Some(region) = matches.opt_str("r") {
+        filter = Expr::and(filter, Some(Expr::Text(Field::Region, Cmp::Eq, region)));
+    }
+    for expr in matches.opt_strs("w") {
+        filter = Expr::and(filter, Some(Expr::parse(&expr)?));
+    }
+    Ok(filter)
 }
 
-fn search<P: AsRef<Path>>(
-    file_path: Option<P>,
-    city: &str,
-) -> Result<Vec<PopulationCount>, CliError> {
-    let mut found = vec![];
+fn output_format(matches: &getopts::Matches) -> Result<Format, CliError> {
+    match matches.opt_str("format") {
+        None => Ok(Format::Text),
+        Some(name) => Format::parse(&name)
+            .ok_or_else(|| CliError::Args(format!("unknown output format '{}'", name))),
+    }
+}
 
-    let input: Box<dyn io::Read> = match file_path {
-        None => Box::new(io::stdin()),
-        Some(ref file_path) => Box::new(File::open(file_path)?),
+fn selection(matches: &getopts::Matches) -> Result<Selection, CliError> {
+    let number = |name: &str| {
+        matches
+            .opt_get::<usize>(name)
+            .map_err(|err| CliError::Args(format!("invalid --{}: {}", name, err)))
     };
+    let sort = match matches.opt_str("sort") {
+        None => None,
+        Some(name) => Some(
+            SortKey::parse(&name)
+                .ok_or_else(|| CliError::Args(format!("cannot sort results by '{}'", name)))?,
+        ),
+    };
+    // An empty page would read nothing, and so find nothing.
+    let limit = number("limit")?;
+    if limit == Some(0) {
+        return Err(CliError::Args("--limit must be at least 1".to_string()));
+    }
+    Ok(Selection {
+        sort,
+        descending: matches.opt_present("desc"),
+        limit,
+        offset: number("offset")?.unwrap_or(0),
+        missing: missing(matches)?,
+    })
+}
+
+// How many threads to search a CSV file with: by default one per CPU.
+fn threads(matches: &getopts::Matches) -> Result<usize, CliError> {
+    match matches.opt_get::<usize>("threads") {
+        Ok(Some(0)) => Err(CliError::Args("--threads must be at least 1".to_string())),
+        Ok(Some(threads)) => Ok(threads),
+        Ok(None) => Ok(thread::available_parallelism().map_or(1, |n| n.get())),
+        Err(err) => Err(CliError::Args(format!("invalid --threads: {}", err))),
+    }
+}
+
+fn run(program: &str, opts: &Options, matches: &getopts::Matches) -> Result<(), CliError> {
+    if matches.opt_present("h") {
+        print_usage(program, opts);
+        return Ok(());
+    }
 
-    // let file = File::open(file_path.as_ref())?;
-    let mut reader = csv::Reader::from_reader(input);
+    let data_path = matches.opt_str("f");
 
-    for result in reader.deserialize() {
-        let record: Record = result?;
+    if matches.free.first().is_some_and(|cmd| cmd == "import") {
+        let db_path = matches
+            .opt_str("db")
+            .ok_or_else(|| CliError::Args("import needs --db <db-path>".to_string()))?;
+        let data_path = matches.free.get(1).or(data_path.as_ref());
+        let mut records = open_records(matches, data_path)?;
+        let imported = db::import(&mut records, &db_path)?;
+        report_skipped(matches, records.diagnostics());
+        if !matches.opt_present("q") {
+            eprintln!("Imported {} rows into {}.", imported, db_path);
+        }
+        return Ok(());
+    }
 
-        match record.population {
-            Some(count) => {
-                if record.city == city {
-                    found.push(PopulationCount {
-                        city: record.city,
-                        country: record.country,
-                        count: count,
-                    });
-                }
+    if matches.free.first().is_some_and(|cmd| cmd == "stats") {
+        let filter = build_filter(matches, None)?;
+        let by = match matches.opt_str("by") {
+            None => GroupBy::Country,
+            Some(by) => GroupBy::parse(&by)
+                .ok_or_else(|| CliError::Args(format!("cannot group by '{}'", by)))?,
+        };
+        let top = matches
+            .opt_get_default("top", 10)
+            .map_err(|err| CliError::Args(format!("invalid --top: {}", err)))?;
+        let mut records = open_records(matches, data_path.as_ref())?;
+        let summary = stats::summarize(&mut records, filter.as_ref(), by, top)?;
+        write!(io::stdout().lock(), "{}", summary)?;
+        report_skipped(matches, records.diagnostics());
+        return Ok(());
+    }
+
+    if matches.free.first().is_some_and(|cmd| cmd == "near") {
+        let (lat, lon) = match (matches.free.get(1), matches.free.get(2)) {
+            (Some(lat), Some(lon)) => geo::parse_coordinates(lat, lon)?,
+            _ => {
+                return Err(CliError::Args(
+                    "near needs a latitude and a longitude".to_string(),
+                ))
             }
-            None => {} // skip it
+        };
+        let radius = geo::parse_radius(&matches.opt_str("radius").unwrap_or("50km".to_string()))?;
+        let format = output_format(matches)?;
+        let filter = build_filter(matches, None)?;
+
+        let mut records = open_records(matches, data_path.as_ref())?;
+        let index = geo::GeoIndex::build(&mut records, filter.as_ref())?;
+        report_skipped(matches, records.diagnostics());
+        let nearby = index.near((lat, lon), radius);
+        if nearby.is_empty() {
+            return Err(CliError::NotFound(vec![]));
         }
+        return output::write_rows(io::stdout().lock(), format, &nearby);
     }
 
-    if found.is_empty() {
-        Err(CliError::NotFound)
-    } else {
-        Ok(found)
+    if matches.free.first().is_some_and(|cmd| cmd == "history") {
+        let city = match matches.free.get(1) {
+            Some(city) => city,
+            None => return Err(CliError::Args("history needs a city".to_string())),
+        };
+        let format = output_format(matches)?;
+        // There is always a filter, as there is a city.
+        let filter = build_filter(matches, Some(city))?.unwrap();
+
+        let mut records = open_records(matches, data_path.as_ref())?;
+        let found = history::history(&mut records, &filter);
+        report_skipped(matches, records.diagnostics());
+        let found = found?;
+        let mut out = io::stdout().lock();
+        if format != Format::Text {
+            let rows: Vec<_> = found.iter().flat_map(history::Series::rows).collect();
+            return output::write_rows(out, format, &rows);
+        }
+        for (i, series) in found.iter().enumerate() {
+            if i > 0 {
+                writeln!(out)?;
+            }
+            write!(out, "{}", series)?;
+        }
+        return Ok(());
     }
+
+    if matches.free.first().is_some_and(|cmd| cmd == "diff") {
+        let (old_path, new_path) = match (matches.free.get(1), matches.free.get(2)) {
+            (Some(old_path), Some(new_path)) => (old_path, new_path),
+            _ => return Err(CliError::Args("diff needs two data paths".to_string())),
+        };
+        let sort = match matches.opt_str("sort") {
+            None => diff::SortBy::Input,
+            Some(sort) => diff::SortBy::parse(&sort)
+                .ok_or_else(|| CliError::Args(format!("cannot sort a diff by '{}'", sort)))?,
+        };
+        let format = output_format(matches)?;
+        let filter = build_filter(matches, None)?;
+
+        let mut old = open_records(matches, Some(old_path))?;
+        let mut new = open_records(matches, Some(new_path))?;
+        let mut diff = diff::diff(&mut old, &mut new, filter.as_ref())?;
+        report_skipped(matches, old.diagnostics());
+        report_skipped(matches, new.diagnostics());
+        diff.sort(sort);
+        if !matches.opt_present("q") {
+            eprintln!(
+                "{} added, {} removed, {} changed, {} unchanged.",
+                diff.count(diff::Status::Added),
+                diff.count(diff::Status::Removed),
+                diff.count(diff::Status::Changed),
+                diff.unchanged
+            );
+        }
+        return output::write_rows(io::stdout().lock(), format, &diff.growth);
+    }
+
+    if matches.free.first().is_some_and(|cmd| cmd == "shell") {
+        let mut records = open_records(matches, data_path.as_ref())?;
+        let index = CityIndex::read(&mut records)?;
+        report_skipped(matches, records.diagnostics());
+        return shell::run(index);
+    }
+
+    if matches.free.first().is_some_and(|cmd| cmd == "serve") {
+        let port: u16 = matches
+            .opt_get_default("port", 8080)
+            .map_err(|err| CliError::Args(format!("invalid --port: {}", err)))?;
+        let host = matches.opt_str("host").unwrap_or("127.0.0.1".to_string());
+
+        let mut records = open_records(matches, data_path.as_ref())?;
+        let index = CityIndex::read(&mut records)?;
+        report_skipped(matches, records.diagnostics());
+        let server = Server::bind((host.as_str(), port), index)?;
+        if !matches.opt_present("q") {
+            eprintln!("Listening on http://{}/", server.local_addr()?);
+        }
+        return server.run();
+    }
+
+    if matches.free.first().is_some_and(|cmd| cmd == "validate") {
+        let mut records = open_records(matches, data_path.as_ref())?;
+        let report = validate::validate(&mut records)?;
+        write!(io::stdout().lock(), "{}", report)?;
+        return match report.diagnostics.len() {
+            0 => Ok(()),
+            invalid => Err(CliError::InvalidRows(invalid)),
+        };
+    }
+
+    let format = output_format(matches)?;
+    let selection = selection(matches)?;
+    let filter = match build_filter(matches, matches.free.first())? {
+        Some(filter) => filter,
+        None => {
+            print_usage(program, opts);
+            return Ok(());
+        }
+    };
+
+    let found = match matches.opt_str("db") {
+        Some(db_path) => {
+            let rows = db::search(db_path, &filter, selection.missing)?;
+            // Every match is at hand, so the count is exact even if the page
+            // fills up before the last of them.
+            let missing = rows.iter().filter(|row| row.count.is_none()).count();
+            // The database was imported without the check, so it is made here.
+            if selection.missing == Missing::Error && missing > 0 {
+                return Err(CliError::InvalidRows(missing));
+            }
+            Found {
+                missing,
+                partial: false,
+                ..selection.apply(rows)
+            }
+        }
+        None => {
+            let source = data_source(data_path.as_ref())?;
+            let source = source.as_deref();
+            let format = input_format(matches, source)?;
+            match source.and_then(|source| source.local_file()) {
+                // A CSV file can be split up and read on several threads.
+                Some(path) if format == InputFormat::Csv => {
+                    let mut scanner =
+                        Scanner::new(path, &csv_options(matches)?, threads(matches)?)?;
+                    let found = scanner.search(&filter, &selection);
+                    report_skipped(matches, scanner.diagnostics());
+                    found?
+                }
+                _ => {
+                    let mut records = read_source(matches, source)?;
+                    let found = search_with(&mut records, &filter, &selection);
+                    report_skipped(matches, records.diagnostics());
+                    found?
+                }
+            }
+        }
+    };
+    if found.missing > 0 && !matches.opt_present("q") {
+        // Reading stopped once the page was full, so there may be more.
+        let count = match found.partial {
+            true => format!("at least {}", found.missing),
+            false => found.missing.to_string(),
+        };
+        match selection.missing {
+            Missing::Include => eprintln!(
+                "Showing {} matching rows without a population as unknown.",
+                count
+            ),
+            _ => eprintln!("Skipped {} matching rows without a population.", count),
+        }
+    }
+    output::write_rows(io::stdout().lock(), format, &found.rows)
 }
 
 fn main() {
@@ -116,40 +404,147 @@ fn main() {
     opts.optopt(
         "f",
         "file",
-        "Choose an input file, instead of using STDIN.",
-        "NAME",
+        "Choose an input file, instead of using STDIN: a path, or file://, gzip:// (or a path ending in .gz) or http:// followed by one. Downloads are cached and only fetched again when they change.",
+        "SOURCE",
+    );
+    opts.optopt(
+        "",
+        "db",
+        "Query (or, for import, create) a SQLite database instead of a CSV file.",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "input-format",
+        "Read the input as csv, json (an array of objects), ndjson or parquet. By default this follows the file extension, and standard input is CSV.",
+        "FORMAT",
+    );
+    opts.optopt(
+        "d",
+        "delimiter",
+        "Field delimiter of the input, e.g. ';' or '\\t' (default ',').",
+        "CHAR",
+    );
+    opts.optflag(
+        "",
+        "no-header",
+        "The input has no header row; columns are city, region, country, population unless mapped.",
+    );
+    opts.optmulti(
+        "",
+        "map",
+        "Read COLUMN (city, region, country or population) from the input column named SOURCE, or numbered SOURCE with --no-header.",
+        "COLUMN=SOURCE",
+    );
+    opts.optflag(
+        "",
+        "lenient",
+        "Skip rows that cannot be read, and list them on stderr, instead of stopping.",
+    );
+    opts.optmulti(
+        "w",
+        "where",
+        "Only keep rows matching EXPR, e.g. 'country=\"United States\" and population>50000'. Repeated filters must all match.",
+        "EXPR",
+    );
+    opts.optopt(
+        "r",
+        "region",
+        "Only keep cities in REGION, e.g. a US state code.",
+        "REGION",
+    );
+    opts.optflag(
+        "",
+        "exact",
+        "Match <city> exactly instead of ignoring case and accents.",
+    );
+    opts.optopt(
+        "",
+        "format",
+        "Output format: text (the default), table, csv, json or ndjson.",
+        "FORMAT",
+    );
+    opts.optopt(
+        "",
+        "by",
+        "stats: group by 'country' (the default) or 'region'.",
+        "COLUMN",
+    );
+    opts.optopt(
+        "",
+        "top",
+        "stats: how many of the largest cities to list (default 10).",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "radius",
+        "near: how far to look, e.g. 500m, 30mi or 50km (the default).",
+        "DISTANCE",
+    );
+    opts.optopt(
+        "",
+        "sort",
+        "Sort results by population, city or country, instead of in input order. For diff: by change, percent, city or input (the default).",
+        "KEY",
+    );
+    opts.optflag("", "desc", "Sort results in descending order.");
+    opts.optopt("", "limit", "Output at most N results.", "N");
+    opts.optopt(
+        "",
+        "offset",
+        "Skip the first N results, e.g. for the next page after --limit.",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "missing",
+        "What to do with rows without a population: skip them (the default), include them as unknown, or treat them as an error.",
+        "skip|include|error",
+    );
+    opts.optopt(
+        "",
+        "threads",
+        "Search a CSV file with N threads (default: one per CPU).",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "port",
+        "serve: the port to listen on (default 8080).",
+        "PORT",
+    );
+    opts.optopt(
+        "",
+        "host",
+        "serve: the address to listen on (default 127.0.0.1).",
+        "ADDR",
     );
     opts.optflag("h", "help", "Show this usage message.");
     opts.optflag("q", "quiet", "Silences errors and warnings.");
 
-    let matches = match opts.parse(&args[1..]) {
-        Ok(m) => m,
-        Err(e) => {
-            panic!("{}", e.to_string())
-        }
-    };
-
-    if matches.opt_present("h") {
-        print_usage(&program, opts);
-        return;
-    }
-
-    let data_path = matches.opt_str("f");
+    // If the arguments cannot be parsed we still want to honour `-q`.
+    let mut quiet = args[1..].iter().any(|arg| arg == "-q" || arg == "--quiet");
+    let result = opts
+        .parse(&args[1..])
+        .map_err(|err| CliError::Args(err.to_string()))
+        .and_then(|matches| {
+            quiet = matches.opt_present("q");
+            run(program, &opts, &matches)
+        });
 
-    let city = if !matches.free.is_empty() {
-        &matches.free[0]
-    } else {
-        print_usage(&program, opts);
-        return;
-    };
-
-    match search(data_path.as_ref(), city) {
-        Err(CliError::NotFound) if matches.opt_present("q") => process::exit(1),
-        Err(err) => panic!("{}", err),
-        Ok(pops) => {
-            for pop in pops {
-                println!("{}, {}: {:?}", pop.city, pop.country, pop.count);
+    match result {
+        Ok(()) => {}
+        // The reader of our output went away, e.g. `city-pop ... | head`.
+        Err(CliError::IoError(ref err)) if err.kind() == io::ErrorKind::BrokenPipe => {}
+        Err(err) => {
+            if !quiet {
+                eprintln!("{}: {}", program, err);
+                if let CliError::Args(_) | CliError::Query(_) = err {
+                    eprintln!("Try '{} --help' for more information.", program);
+                }
             }
+            process::exit(err.exit_code());
         }
     }
 }
@@ -0,0 +1,472 @@
+// Reading records from inputs whose rows are objects with named fields: JSON
+// arrays, NDJSON and Parquet files. Parquet rows are converted to JSON
+// objects first, so that all three share the same conversion to `Record`.
+use bytes::Bytes;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::reader::RowIter;
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+use std::io::{BufRead, BufReader, Read};
+
+use crate::ingest::{self, CsvOptions, Diagnostic, Missing, Problem, RecordStream};
+use crate::{CliError, Record};
+
+// One object of the input and where it was found.
+struct Object {
+    line: u64,
+    byte: u64,
+    fields: Map<String, Value>,
+}
+
+type Objects = Box<dyn Iterator<Item = Result<Object, CliError>>>;
+
+pub struct ObjectRecords {
+    objects: Objects,
+    options: CsvOptions,
+    // The `Record` fields the input has columns for.
+    columns: BTreeSet<String>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl ObjectRecords {
+    // Reads a JSON array of objects. The whole array is parsed up front, so
+    // syntax errors are reported before any record.
+    pub fn from_json<R: Read>(input: R, options: &CsvOptions) -> Result<ObjectRecords, CliError> {
+        let values: Vec<Value> = serde_json::from_reader(input).map_err(CliError::Json)?;
+        let mut objects = vec![];
+        let mut keys = BTreeSet::new();
+        for (i, value) in values.into_iter().enumerate() {
+            let object = to_object(value, i as u64 + 1, 0);
+            if let Ok(ref object) = object {
+                keys.extend(object.fields.keys().cloned());
+            }
+            objects.push(object);
+        }
+        ObjectRecords::new(Box::new(objects.into_iter()), keys, options)
+    }
+
+    // Reads one JSON object per line. Blank lines are ignored. The columns are
+    // those of the first object that can be read.
+    pub fn from_ndjson<R: Read + 'static>(
+        input: R,
+        options: &CsvOptions,
+    ) -> Result<ObjectRecords, CliError> {
+        let mut lines = NdjsonLines {
+            input: BufReader::new(input),
+            line: 0,
+            byte: 0,
+        };
+
+        // Look ahead for the first object, keeping everything read on the way.
+        let mut head = vec![];
+        let mut keys = BTreeSet::new();
+        for object in lines.by_ref() {
+            let found = match object {
+                Ok(ref object) => {
+                    keys.extend(object.fields.keys().cloned());
+                    true
+                }
+                Err(CliError::InvalidRow(_)) => false,
+                Err(err) => return Err(err),
+            };
+            head.push(object);
+            if found {
+                break;
+            }
+        }
+        ObjectRecords::new(Box::new(head.into_iter().chain(lines)), keys, options)
+    }
+
+    // Reads a Parquet file. Parquet needs random access, so the whole input
+    // is read into memory first.
+    pub fn from_parquet<R: Read>(
+        mut input: R,
+        options: &CsvOptions,
+    ) -> Result<ObjectRecords, CliError> {
+        let mut data = vec![];
+        input.read_to_end(&mut data)?;
+        let reader = SerializedFileReader::new(Bytes::from(data)).map_err(CliError::Parquet)?;
+        let keys = reader
+            .metadata()
+            .file_metadata()
+            .schema()
+            .get_fields()
+            .iter()
+            .map(|field| field.name().to_string())
+            .collect();
+
+        let rows =
+            RowIter::from_file_into(Box::new(reader))
+                .enumerate()
+                .map(|(i, row)| match row {
+                    Ok(row) => to_object(row.to_json_value(), i as u64 + 1, 0),
+                    Err(err) => Err(CliError::Parquet(err)),
+                });
+        ObjectRecords::new(Box::new(rows), keys, options)
+    }
+
+    fn new(
+        objects: Objects,
+        keys: BTreeSet<String>,
+        options: &CsvOptions,
+    ) -> Result<ObjectRecords, CliError> {
+        for (_, source) in &options.mapping {
+            if !keys.iter().any(|key| key.trim() == source.trim()) {
+                return Err(CliError::MissingColumn(source.clone()));
+            }
+        }
+        let columns: BTreeSet<String> = keys
+            .iter()
+            .filter_map(|key| options.field_for(key))
+            .collect();
+        ingest::require_columns(|column| columns.contains(column))?;
+
+        Ok(ObjectRecords {
+            objects,
+            options: options.clone(),
+            columns,
+            diagnostics: vec![],
+        })
+    }
+
+    fn record(&self, object: Object) -> Result<Record, Diagnostic> {
+        let mut fields = Map::new();
+        for (key, value) in object.fields {
+            if let Some(field) = self.options.field_for(&key) {
+                fields.insert(field, value);
+            }
+        }
+
+        let diagnostic = |column: &str, value: Option<&Value>, problem: Problem| Diagnostic {
+            line: object.line,
+            byte: object.byte,
+            column: Some(column.to_string()),
+            value: value.map(|value| match value {
+                Value::String(text) => text.clone(),
+                value => value.to_string(),
+            }),
+            problem,
+        };
+        let text = |column: &str| match fields.get(column) {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::String(text)) => Ok(Some(text.clone())),
+            Some(Value::Number(number)) => Ok(Some(number.to_string())),
+            Some(value) => Err(diagnostic(
+                column,
+                Some(value),
+                Problem::Malformed("expected text".to_string()),
+            )),
+        };
+        let required = |column: &str| {
+            text(column)?.ok_or_else(|| {
+                diagnostic(
+                    column,
+                    None,
+                    Problem::Malformed("missing value".to_string()),
+                )
+            })
+        };
+        let coordinate = |column: &str| {
+            let value = fields.get(column);
+            let parsed = match value {
+                None | Some(Value::Null) => Some(None),
+                Some(Value::Number(number)) => number.as_f64().map(Some),
+                Some(Value::String(text)) if text.trim().is_empty() => Some(None),
+                Some(Value::String(text)) => text.trim().parse().ok().map(Some),
+                Some(_) => None,
+            };
+            parsed.ok_or_else(|| {
+                diagnostic(
+                    column,
+                    value,
+                    Problem::Malformed("expected a number".to_string()),
+                )
+            })
+        };
+
+        let population = fields.get("population");
+        let population = match population {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::Number(number)) => number.as_u64().map(Some).ok_or(()),
+            Some(Value::String(text)) => ingest::parse_population(text).map_err(|_| ()),
+            Some(_) => Err(()),
+        }
+        .map_err(|_| diagnostic("population", population, Problem::InvalidPopulation))?;
+        if population.is_none() && self.options.missing == Missing::Error {
+            return Err(diagnostic(
+                "population",
+                fields.get("population"),
+                Problem::MissingPopulation,
+            ));
+        }
+
+        let year = fields.get("year");
+        let year = match year {
+            None | Some(Value::Null) => Some(None),
+            Some(Value::Number(number)) => {
+                number.as_i64().and_then(|y| y.try_into().ok()).map(Some)
+            }
+            Some(Value::String(text)) if text.trim().is_empty() => Some(None),
+            Some(Value::String(text)) => text.trim().parse().ok().map(Some),
+            Some(_) => None,
+        }
+        .ok_or_else(|| {
+            diagnostic(
+                "year",
+                year,
+                Problem::Malformed("expected a year".to_string()),
+            )
+        })?;
+
+        Ok(Record {
+            country: required("country")?,
+            city: required("city")?,
+            region: text("region")?,
+            population,
+            latitude: coordinate("latitude")?,
+            longitude: coordinate("longitude")?,
+            year,
+        })
+    }
+}
+
+impl RecordStream for ObjectRecords {
+    fn has_column(&self, name: &str) -> bool {
+        self.columns.contains(name)
+    }
+
+    fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+}
+
+impl Iterator for ObjectRecords {
+    type Item = Result<Record, CliError>;
+
+    fn next(&mut self) -> Option<Result<Record, CliError>> {
+        loop {
+            let result = match self.objects.next()? {
+                Ok(object) => self.record(object).map_err(CliError::InvalidRow),
+                Err(err) => Err(err),
+            };
+            match result {
+                Err(CliError::InvalidRow(diagnostic)) if self.options.lenient => {
+                    self.diagnostics.push(diagnostic)
+                }
+                result => return Some(result),
+            }
+        }
+    }
+}
+
+fn to_object(value: Value, line: u64, byte: u64) -> Result<Object, CliError> {
+    match value {
+        Value::Object(fields) => Ok(Object { line, byte, fields }),
+        _ => Err(CliError::InvalidRow(Diagnostic {
+            line,
+            byte,
+            column: None,
+            value: None,
+            problem: Problem::Malformed("expected a JSON object".to_string()),
+        })),
+    }
+}
+
+struct NdjsonLines<R> {
+    input: BufReader<R>,
+    // The number of lines read so far and the offset of the next one.
+    line: u64,
+    byte: u64,
+}
+
+impl<R: Read> Iterator for NdjsonLines<R> {
+    type Item = Result<Object, CliError>;
+
+    fn next(&mut self) -> Option<Result<Object, CliError>> {
+        let mut buf = vec![];
+        let start = loop {
+            let start = self.byte;
+            buf.clear();
+            match self.input.read_until(b'\n', &mut buf) {
+                Ok(0) => return None,
+                Ok(len) => self.byte += len as u64,
+                Err(err) => return Some(Err(err.into())),
+            }
+            self.line += 1;
+            if !buf.trim_ascii().is_empty() {
+                break start;
+            }
+        };
+
+        let malformed = |reason: String| {
+            CliError::InvalidRow(Diagnostic {
+                line: self.line,
+                byte: start,
+                column: None,
+                value: None,
+                problem: Problem::Malformed(reason),
+            })
+        };
+        Some(match std::str::from_utf8(&buf) {
+            Err(_) => Err(malformed("invalid UTF-8".to_string())),
+            Ok(text) => match serde_json::from_str(text) {
+                Ok(value) => to_object(value, self.line, start),
+                Err(err) => Err(malformed(err.to_string())),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::InputFormat;
+    use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    fn read(format: InputFormat, data: Vec<u8>) -> Vec<Record> {
+        CsvOptions::default()
+            .open(Cursor::new(data), format)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn springfield(region: &str, population: Option<u64>) -> Record {
+        Record {
+            country: "United States".to_string(),
+            city: "Springfield".to_string(),
+            region: Some(region.to_string()),
+            population,
+            latitude: None,
+            longitude: None,
+            year: None,
+        }
+    }
+
+    #[test]
+    fn reads_json_arrays() {
+        let data = r#"[
+            {"City": "Springfield", "region": "MA", "country": "United States",
+             "population": 152227, "lat": 42.1, "lng": "-72.59"},
+            {"city": "Springfield", "region": "MO", "country": "United States",
+             "population": "150,443"},
+            {"city": "Springfield", "region": "NJ", "country": "United States",
+             "population": null, "founded": 1664}
+        ]"#;
+        let records = read(InputFormat::Json, data.into());
+
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].latitude, Some(42.1));
+        assert_eq!(records[0].longitude, Some(-72.59));
+        assert_eq!(records[1], springfield("MO", Some(150443)));
+        assert_eq!(records[2], springfield("NJ", None));
+    }
+
+    #[test]
+    fn reads_ndjson_leniently() {
+        let data = "{\"city\": \"Springfield\", \"region\": \"MA\", \"country\": \"United States\", \"population\": 152227}\n\
+                    \n\
+                    {\"city\": \"Springfield\", \"region\": \"OH\", \"country\": \"United States\", \"population\": -1}\n\
+                    {\"city\": \"Springfield\", \"region\": \n\
+                    {\"city\": \"Springfield\", \"region\": \"OR\", \"country\": \"United States\", \"population\": 56032}\n";
+        let options = CsvOptions {
+            lenient: true,
+            ..CsvOptions::default()
+        };
+        let mut records = options.open(data.as_bytes(), InputFormat::Ndjson).unwrap();
+        let found: Vec<_> = records.by_ref().map(Result::unwrap).collect();
+        assert_eq!(
+            found,
+            vec![
+                springfield("MA", Some(152227)),
+                springfield("OR", Some(56032))
+            ]
+        );
+        assert!(records.has_column("region"));
+        assert!(!records.has_column("latitude"));
+
+        let skipped = records.diagnostics();
+        assert_eq!(skipped.len(), 2);
+        assert_eq!(skipped[0].line, 3);
+        assert_eq!(skipped[0].problem, Problem::InvalidPopulation);
+        assert_eq!(skipped[0].value.as_deref(), Some("-1"));
+        assert_eq!(skipped[1].line, 4);
+        assert_eq!(skipped[1].byte, 177);
+    }
+
+    // Two Springfields, with the region in a `state` column and the second
+    // population missing.
+    fn parquet() -> Vec<u8> {
+        let schema = parse_message_type(
+            "message cities {
+                REQUIRED BYTE_ARRAY city (UTF8);
+                REQUIRED BYTE_ARRAY state (UTF8);
+                REQUIRED BYTE_ARRAY country (UTF8);
+                OPTIONAL INT64 population;
+            }",
+        )
+        .unwrap();
+        let mut data = vec![];
+        let mut file =
+            SerializedFileWriter::new(&mut data, Arc::new(schema), Default::default()).unwrap();
+        let mut group = file.next_row_group().unwrap();
+        for column in [&["Springfield"; 2], &["MA", "MO"], &["United States"; 2]] {
+            let values: Vec<ByteArray> = column.iter().map(|&value| value.into()).collect();
+            let mut writer = group.next_column().unwrap().unwrap();
+            writer
+                .typed::<ByteArrayType>()
+                .write_batch(&values, None, None)
+                .unwrap();
+            writer.close().unwrap();
+        }
+        let mut writer = group.next_column().unwrap().unwrap();
+        writer
+            .typed::<Int64Type>()
+            .write_batch(&[152227], Some(&[1, 0]), None)
+            .unwrap();
+        writer.close().unwrap();
+        group.close().unwrap();
+        file.close().unwrap();
+        data
+    }
+
+    #[test]
+    fn reads_parquet() {
+        let options = CsvOptions {
+            mapping: vec![("region".to_string(), "state".to_string())],
+            ..CsvOptions::default()
+        };
+        let records: Vec<_> = options
+            .open(Cursor::new(parquet()), InputFormat::Parquet)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            records,
+            vec![springfield("MA", Some(152227)), springfield("MO", None)]
+        );
+    }
+
+    #[test]
+    fn detects_formats() {
+        assert_eq!(InputFormat::from_path("cities.JSON"), InputFormat::Json);
+        assert_eq!(InputFormat::from_path("cities.jsonl"), InputFormat::Ndjson);
+        assert_eq!(
+            InputFormat::from_path("cities.parquet"),
+            InputFormat::Parquet
+        );
+        assert_eq!(InputFormat::from_path("cities.tsv"), InputFormat::Csv);
+        assert!(matches!(
+            CsvOptions::default().open("[{\"city\": \"Paris\"}]".as_bytes(), InputFormat::Json),
+            Err(CliError::MissingColumn(_))
+        ));
+        assert!(matches!(
+            CsvOptions::default().open("[{".as_bytes(), InputFormat::Json),
+            Err(CliError::Json(_))
+        ));
+    }
+}
@@ -0,0 +1,200 @@
+use serde::Serialize;
+use std::io::{self, Write};
+
+use crate::{CliError, PopulationCount};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    // One `city, region, country: count` line per result.
+    Text,
+    // Aligned columns with a header, for reading in a terminal.
+    Table,
+    Csv,
+    // A single JSON array.
+    Json,
+    // One JSON object per line.
+    Ndjson,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "table" => Some(Format::Table),
+            "csv" => Some(Format::Csv),
+            "json" => Some(Format::Json),
+            "ndjson" => Some(Format::Ndjson),
+            _ => None,
+        }
+    }
+}
+
+// Something that can be written out as one row of results.
+pub trait Row: Serialize {
+    // Column names, in the same order as the serialised fields.
+    const HEADER: &'static [&'static str];
+    // Columns that hold numbers and are right-aligned in tables.
+    const NUMERIC: &'static [&'static str];
+
+    fn cells(&self) -> Vec<String>;
+
+    // The line printed for `--format text`.
+    fn text(&self) -> String;
+}
+
+impl Row for PopulationCount {
+    const HEADER: &'static [&'static str] = &["city", "region", "country", "population"];
+    const NUMERIC: &'static [&'static str] = &["population"];
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.city.clone(),
+            self.region.clone().unwrap_or_default(),
+            self.country.clone(),
+            count(self.count),
+        ]
+    }
+
+    fn text(&self) -> String {
+        match self.region {
+            Some(ref region) => format!(
+                "{}, {}, {}: {}",
+                self.city,
+                region,
+                self.country,
+                count(self.count)
+            ),
+            None => format!("{}, {}: {}", self.city, self.country, count(self.count)),
+        }
+    }
+}
+
+// A population for people to read. CSV and JSON leave a missing one empty.
+pub fn count(count: Option<u64>) -> String {
+    count.map_or("unknown".to_string(), |count| count.to_string())
+}
+
+pub fn write_rows<W: Write, T: Row>(
+    mut out: W,
+    format: Format,
+    rows: &[T],
+) -> Result<(), CliError> {
+    match format {
+        Format::Text => {
+            for row in rows {
+                writeln!(out, "{}", row.text())?;
+            }
+        }
+        Format::Table => write_table(&mut out, rows)?,
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            for row in rows {
+                writer.serialize(row)?;
+            }
+            writer.flush()?;
+        }
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut out, rows).map_err(io::Error::from)?;
+            writeln!(out)?;
+        }
+        Format::Ndjson => {
+            for row in rows {
+                serde_json::to_writer(&mut out, row).map_err(io::Error::from)?;
+                writeln!(out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_table<W: Write, T: Row>(out: &mut W, rows: &[T]) -> io::Result<()> {
+    let header: Vec<String> = T::HEADER.iter().map(|name| name.to_string()).collect();
+    let rows: Vec<Vec<String>> = rows.iter().map(Row::cells).collect();
+
+    // Widths are counted in chars so that names like "São Paulo" line up.
+    let mut widths: Vec<usize> = T::HEADER.iter().map(|name| name.len()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let rule: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+    for row in [&header, &rule].into_iter().chain(&rows) {
+        let mut line = String::new();
+        for ((cell, width), name) in row.iter().zip(&widths).zip(T::HEADER) {
+            let pad = " ".repeat(width - cell.chars().count());
+            if !line.is_empty() {
+                line.push_str("  ");
+            }
+            if T::NUMERIC.contains(name) {
+                line.push_str(&pad);
+                line.push_str(cell);
+            } else {
+                line.push_str(cell);
+                line.push_str(&pad);
+            }
+        }
+        writeln!(out, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pops() -> Vec<PopulationCount> {
+        vec![
+            PopulationCount {
+                city: "São Paulo".to_string(),
+                region: None,
+                country: "Brazil".to_string(),
+                count: Some(12325232),
+            },
+            PopulationCount {
+                city: "Concord".to_string(),
+                region: Some("NH".to_string()),
+                country: "United States".to_string(),
+                count: Some(42605),
+            },
+        ]
+    }
+
+    fn render(format: Format) -> String {
+        let mut out = vec![];
+        write_rows(&mut out, format, &pops()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn renders_aligned_table() {
+        assert_eq!(
+            render(Format::Table),
+            "\
+city       region  country        population
+---------  ------  -------------  ----------
+São Paulo          Brazil           12325232
+Concord    NH      United States       42605
+"
+        );
+    }
+
+    #[test]
+    fn csv_output_can_be_read_back() {
+        assert_eq!(
+            render(Format::Csv),
+            "city,region,country,population\nSão Paulo,,Brazil,12325232\nConcord,NH,United States,42605\n"
+        );
+    }
+
+    #[test]
+    fn renders_json() {
+        assert_eq!(
+            render(Format::Ndjson).lines().nth(1).unwrap(),
+            r#"{"city":"Concord","region":"NH","country":"United States","population":42605}"#
+        );
+        let json: serde_json::Value = serde_json::from_str(&render(Format::Json)).unwrap();
+        assert_eq!(json[0]["region"], serde_json::Value::Null);
+    }
+}
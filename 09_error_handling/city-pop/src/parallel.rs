@@ -0,0 +1,367 @@
+// Scanning a large CSV file on several threads. The file is split into
+// chunks at row boundaries, every chunk is read by its own thread through
+// `Records`, and the results are put back together in file order, so that
+// they are the same as those of a sequential scan.
+use csv::StringRecord;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::{panic, thread};
+
+use crate::fuzzy::Suggester;
+use crate::ingest::{CsvOptions, Diagnostic, RecordStream, Records, Start};
+use crate::query::Expr;
+use crate::select::{Found, Selection};
+use crate::{scan, CliError, Scan};
+
+// Chunks smaller than this are not worth a thread of their own.
+const MIN_CHUNK: u64 = 4 << 20;
+
+#[derive(Debug, Clone, Copy)]
+struct Chunk {
+    // Byte range of the chunk in the file.
+    from: u64,
+    to: u64,
+    start: Start,
+}
+
+pub struct Scanner {
+    path: PathBuf,
+    options: CsvOptions,
+    headers: StringRecord,
+    chunks: Vec<Chunk>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Scanner {
+    // Plans a scan of the CSV at `path` with at most `threads` threads. This
+    // reads the whole file once to find where rows start, as a newline may
+    // be part of a quoted field.
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        options: &CsvOptions,
+        threads: usize,
+    ) -> Result<Scanner, CliError> {
+        Scanner::with_chunk_size(path.as_ref(), options, threads, MIN_CHUNK)
+    }
+
+    fn with_chunk_size(
+        path: &Path,
+        options: &CsvOptions,
+        threads: usize,
+        min_chunk: u64,
+    ) -> Result<Scanner, CliError> {
+        let len = File::open(path)?.metadata()?.len();
+        // Reading the header row also checks for the required columns.
+        let head = options.records(File::open(path)?)?;
+        let headers = head.headers().clone();
+
+        let (from, lines, fields) = if options.has_headers {
+            let pos = head.position();
+            (pos.byte(), pos.line() - 1, headers.len() as u64)
+        } else {
+            let mut reader = csv::ReaderBuilder::new()
+                .delimiter(options.delimiter)
+                .has_headers(false)
+                .from_path(path)?;
+            let mut first = StringRecord::new();
+            reader.read_record(&mut first)?;
+            (0, 0, first.len() as u64)
+        };
+
+        let count = threads
+            .min((len.saturating_sub(from) / min_chunk) as usize)
+            .max(1);
+        let mut chunks = vec![];
+        let mut start = Start {
+            lines,
+            bytes: from,
+            fields: Some(fields),
+        };
+        for (to, lines_before) in row_boundaries(path, from, len, count)? {
+            chunks.push(Chunk {
+                from: start.bytes,
+                to,
+                start,
+            });
+            start.bytes = to;
+            start.lines = lines + lines_before;
+        }
+        chunks.push(Chunk {
+            from: start.bytes,
+            to: len,
+            start,
+        });
+
+        Ok(Scanner {
+            path: path.to_path_buf(),
+            options: options.clone(),
+            headers,
+            chunks,
+            diagnostics: vec![],
+        })
+    }
+
+    // The number of chunks, and so of threads, the file is read with.
+    pub fn chunks(&self) -> usize {
+        self.chunks.len()
+    }
+
+    // The rows skipped by the last scan in lenient mode, in file order.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    // Runs `f` over the records of every chunk, each on its own thread. The
+    // results come in file order, with the rows each chunk skipped.
+    fn map<T, F>(&self, f: F) -> Vec<(Result<T, CliError>, Vec<Diagnostic>)>
+    where
+        T: Send,
+        F: Fn(&mut Records<io::Take<File>>) -> Result<T, CliError> + Sync,
+    {
+        let read = |chunk: &Chunk| {
+            let mut file = File::open(&self.path)?;
+            file.seek(SeekFrom::Start(chunk.from))?;
+            let input = file.take(chunk.to - chunk.from);
+            Ok(self.options.chunk(input, &self.headers, chunk.start))
+        };
+        thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .chunks
+                .iter()
+                .map(|chunk| {
+                    scope.spawn(|| match read(chunk) {
+                        Ok(mut records) => {
+                            let result = f(&mut records);
+                            (result, records.diagnostics().to_vec())
+                        }
+                        Err(err) => (Err(err), vec![]),
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|err| panic::resume_unwind(err))
+                })
+                .collect()
+        })
+    }
+
+    // The same as `search_with` over the whole file. When a page without a
+    // sort order fills up, the result is partial either way, but the rows
+    // without a population counted can differ: chunks count some that a
+    // sequential scan never gets to.
+    pub fn search(&mut self, filter: &Expr, selection: &Selection) -> Result<Found, CliError> {
+        // Every chunk keeps the rows that could make it onto the page; the
+        // selection is then applied once more to all of them, in file order.
+        let window = Selection {
+            offset: 0,
+            limit: selection
+                .limit
+                .map(|limit| limit.saturating_add(selection.offset)),
+            ..*selection
+        };
+        let results = self.map(|records| scan(records, filter, &window));
+
+        self.diagnostics.clear();
+        let mut matched = false;
+        let mut found = vec![];
+        let mut missing = 0;
+        let mut partial = false;
+        let mut suggester = Suggester::for_filter(filter);
+        for (result, diagnostics) in results {
+            // Unsorted, a sequential scan stops once the page is full and
+            // never sees what comes after, including any errors.
+            if selection.sort.is_none() && window.limit.is_some_and(|limit| found.len() >= limit) {
+                partial = true;
+                break;
+            }
+            self.diagnostics.extend(diagnostics);
+            match result? {
+                Scan::Found(chunk) => {
+                    matched = true;
+                    found.extend(chunk.rows);
+                    missing += chunk.missing;
+                    partial |= chunk.partial;
+                }
+                // Each chunk made its own suggestions; the best of them are
+                // the best overall.
+                Scan::NotFound(chunk) => {
+                    if let (Some(suggester), Some(chunk)) = (suggester.as_mut(), chunk) {
+                        suggester.merge(chunk);
+                    }
+                }
+            }
+        }
+
+        if matched {
+            let rows = selection.apply(found).rows;
+            return Ok(Found {
+                rows,
+                missing,
+                partial,
+            });
+        }
+        Err(CliError::NotFound(
+            suggester.map_or(vec![], Suggester::into_suggestions),
+        ))
+    }
+}
+
+// Splits the bytes `from..len` of a CSV into `count` parts of about the same
+// size, moving each split to the start of the next row. Returns the offset
+// of every split and the number of lines between `from` and it. Quotes are
+// taken to only ever enclose whole fields, as RFC 4180 has it.
+fn row_boundaries(path: &Path, from: u64, len: u64, count: usize) -> io::Result<Vec<(u64, u64)>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(from))?;
+    let mut input = BufReader::with_capacity(1 << 16, file);
+
+    let target = |i: usize| from + (len - from) * i as u64 / count as u64;
+    let mut boundaries = vec![];
+    let mut offset = from;
+    let mut lines = 0;
+    let mut quoted = false;
+    while boundaries.len() + 1 < count {
+        let buf = input.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        for &byte in buf {
+            offset += 1;
+            match byte {
+                // An escaped quote inside a field toggles twice.
+                b'"' => quoted = !quoted,
+                b'\n' if !quoted => {
+                    lines += 1;
+                    if offset >= target(boundaries.len() + 1) && offset < len {
+                        boundaries.push((offset, lines));
+                        if boundaries.len() + 1 == count {
+                            break;
+                        }
+                    }
+                }
+                b'\n' => lines += 1,
+                _ => {}
+            }
+        }
+        let consumed = buf.len();
+        input.consume(consumed);
+    }
+    Ok(boundaries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::Problem;
+    use crate::search_with;
+    use crate::select::SortKey;
+    use std::{env, fs, process};
+
+    // A CSV with quoted newlines and commas, and a few bad rows.
+    fn data() -> String {
+        let mut data = String::from("city,region,country,population\n");
+        for i in 0..300 {
+            match i % 50 {
+                7 => data.push_str("\"Spring\nfield\",MA,United States,\"1,000\"\n"),
+                21 => data.push_str("Springfield,MO,United States\n"),
+                33 => data.push_str("Springfield,NJ,United States,lots\n"),
+                _ => data.push_str(&format!(
+                    "Springfield,\"R{}\",United States,{}\n",
+                    i,
+                    i % 17
+                )),
+            }
+        }
+        data
+    }
+
+    struct TempFile(PathBuf);
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn temp_file(name: &str, data: &str) -> TempFile {
+        let path = env::temp_dir().join(format!("city-pop-{}-{}.csv", process::id(), name));
+        fs::write(&path, data).unwrap();
+        TempFile(path)
+    }
+
+    #[test]
+    fn matches_sequential_scan() {
+        let data = data();
+        let file = temp_file("scan", &data);
+        let options = CsvOptions {
+            lenient: true,
+            ..CsvOptions::default()
+        };
+        let filter = Expr::parse("city=Springfield or population>5").unwrap();
+
+        for selection in [
+            Selection::default(),
+            Selection {
+                limit: Some(7),
+                offset: 3,
+                ..Selection::default()
+            },
+            Selection {
+                sort: Some(SortKey::Population),
+                descending: true,
+                limit: Some(20),
+                offset: 5,
+                ..Selection::default()
+            },
+        ] {
+            let mut records = options.records(data.as_bytes()).unwrap();
+            let expected = search_with(&mut records, &filter, &selection).unwrap();
+
+            let mut scanner = Scanner::with_chunk_size(&file.0, &options, 8, 64).unwrap();
+            assert_eq!(scanner.chunks(), 8);
+            assert_eq!(scanner.search(&filter, &selection).unwrap(), expected);
+            if selection.limit.is_none() {
+                assert_eq!(scanner.diagnostics(), records.diagnostics());
+            }
+        }
+
+        // The name is right, so there is nothing to suggest.
+        let filter = Expr::parse("city=Springfield and region=ZZ").unwrap();
+        let mut scanner = Scanner::with_chunk_size(&file.0, &options, 8, 64).unwrap();
+        match scanner.search(&filter, &Selection::default()) {
+            Err(CliError::NotFound(suggestions)) => assert!(suggestions.is_empty()),
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    #[test]
+    fn reports_the_first_bad_row() {
+        let data = data();
+        let file = temp_file("strict", &data);
+        let filter = Expr::parse("population>0").unwrap();
+
+        let mut scanner = Scanner::with_chunk_size(&file.0, &CsvOptions::default(), 4, 64).unwrap();
+        let err = scanner.search(&filter, &Selection::default()).unwrap_err();
+        match err {
+            CliError::InvalidRow(ref diagnostic) => {
+                assert_eq!(diagnostic.line, 24);
+                assert_eq!(
+                    diagnostic.problem,
+                    Problem::FieldCount {
+                        expected: 4,
+                        found: 3
+                    }
+                );
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+        let mut records = CsvOptions::default().records(data.as_bytes()).unwrap();
+        let sequential = search_with(&mut records, &filter, &Selection::default());
+        assert_eq!(sequential.unwrap_err().to_string(), err.to_string());
+    }
+}
@@ -0,0 +1,418 @@
+use regex::Regex;
+use std::{error::Error, fmt};
+
+use crate::{fuzzy, Record};
+
+// A small filter language for selecting rows, e.g.
+//
+//     country="United States" and (population>50000 or city^=Spring)
+//
+// Text columns support `=`, `!=`, `^=` (prefix) and `~` (regex). The
+// population column supports `=`, `!=`, `<`, `<=`, `>` and `>=`; rows with
+// no population never satisfy a population comparison. Terms can be combined
+// with `and`/`&&`, `or`/`||`, `not`/`!` and parentheses.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    City,
+    Region,
+    Country,
+    Population,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Text(Field, Cmp, String),
+    // Equality ignoring case and diacritics. The value is already folded.
+    Folded(Field, String),
+    Prefix(Field, String),
+    Matches(Field, Regex),
+    Population(Cmp, u64),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    // Byte offset into the query where parsing failed.
+    pub pos: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid query at column {}: {}", self.pos + 1, self.msg)
+    }
+}
+
+impl Error for ParseError {}
+
+impl Field {
+    fn text<'a>(&self, record: &'a Record) -> Option<&'a str> {
+        match *self {
+            Field::City => Some(&record.city),
+            Field::Region => record.region.as_deref(),
+            Field::Country => Some(&record.country),
+            Field::Population => None,
+        }
+    }
+}
+
+impl Cmp {
+    fn test<T: PartialOrd + ?Sized>(&self, lhs: &T, rhs: &T) -> bool {
+        match *self {
+            Cmp::Eq => lhs == rhs,
+            Cmp::Ne => lhs != rhs,
+            Cmp::Lt => lhs < rhs,
+            Cmp::Le => lhs <= rhs,
+            Cmp::Gt => lhs > rhs,
+            Cmp::Ge => lhs >= rhs,
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(input: &str) -> Result<Expr, ParseError> {
+        let mut parser = Parser { input, pos: 0 };
+        let expr = parser.parse_or()?;
+        parser.skip_whitespace();
+        if parser.pos < input.len() {
+            return Err(parser.error("unexpected trailing input"));
+        }
+        Ok(expr)
+    }
+
+    // Matches `field` against `value` ignoring case and diacritics.
+    pub fn folded(field: Field, value: &str) -> Expr {
+        Expr::Folded(field, fuzzy::fold(value))
+    }
+
+    // The city name that this filter requires, if there is one, and whether
+    // it is compared ignoring case and diacritics. Used to suggest similar
+    // names when nothing matches.
+    pub fn required_city(&self) -> Option<(&str, bool)> {
+        match *self {
+            Expr::Text(Field::City, Cmp::Eq, ref city) => Some((city, false)),
+            Expr::Folded(Field::City, ref city) => Some((city, true)),
+            Expr::And(ref lhs, ref rhs) => lhs.required_city().or_else(|| rhs.required_city()),
+            _ => None,
+        }
+    }
+
+    // Combines two optional filters so that both have to hold.
+    pub fn and(lhs: Option<Expr>, rhs: Option<Expr>) -> Option<Expr> {
+        match (lhs, rhs) {
+            (Some(lhs), Some(rhs)) => Some(Expr::And(Box::new(lhs), Box::new(rhs))),
+            (lhs, rhs) => lhs.or(rhs),
+        }
+    }
+
+    // Whether any term of the expression looks at `field`.
+    pub fn uses(&self, field: Field) -> bool {
+        match *self {
+            Expr::Text(f, _, _) | Expr::Folded(f, _) | Expr::Prefix(f, _) | Expr::Matches(f, _) => {
+                f == field
+            }
+            Expr::Population(_, _) => field == Field::Population,
+            Expr::And(ref lhs, ref rhs) | Expr::Or(ref lhs, ref rhs) => {
+                lhs.uses(field) || rhs.uses(field)
+            }
+            Expr::Not(ref expr) => expr.uses(field),
+        }
+    }
+
+    pub fn matches(&self, record: &Record) -> bool {
+        match *self {
+            Expr::Text(field, cmp, ref value) => field
+                .text(record)
+                .is_some_and(|text| cmp.test(text, value.as_str())),
+            Expr::Folded(field, ref value) => field
+                .text(record)
+                .is_some_and(|text| fuzzy::fold(text) == *value),
+            Expr::Prefix(field, ref prefix) => field
+                .text(record)
+                .is_some_and(|text| text.starts_with(prefix.as_str())),
+            Expr::Matches(field, ref regex) => {
+                field.text(record).is_some_and(|text| regex.is_match(text))
+            }
+            Expr::Population(cmp, value) => record
+                .population
+                .is_some_and(|count| cmp.test(&count, &value)),
+            Expr::And(ref lhs, ref rhs) => lhs.matches(record) && rhs.matches(record),
+            Expr::Or(ref lhs, ref rhs) => lhs.matches(record) || rhs.matches(record),
+            Expr::Not(ref expr) => !expr.matches(record),
+        }
+    }
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, msg: &str) -> ParseError {
+        ParseError {
+            pos: self.pos,
+            msg: msg.to_string(),
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    // Consumes `symbol` or the case-insensitive keyword `word` if either
+    // comes next.
+    fn eat_operator(&mut self, symbol: &str, word: &str) -> bool {
+        self.skip_whitespace();
+        if self.rest().starts_with(symbol) {
+            self.pos += symbol.len();
+            return true;
+        }
+        // Compared as bytes, as `word.len()` need not fall between two
+        // characters of the input.
+        let rest = self.rest();
+        let is_word = rest
+            .as_bytes()
+            .get(..word.len())
+            .is_some_and(|start| start.eq_ignore_ascii_case(word.as_bytes()))
+            && rest
+                .get(word.len()..)
+                .and_then(|after| after.chars().next())
+                .is_none_or(|c| c.is_whitespace() || c == '(');
+        if is_word {
+            self.pos += word.len();
+        }
+        is_word
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.parse_and()?;
+        while self.eat_operator("||", "or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.parse_unary()?;
+        while self.eat_operator("&&", "and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        if self.eat_operator("!", "not") {
+            return Ok(Expr::Not(Box::new(self.parse_unary()?)));
+        }
+        if self.rest().starts_with('(') {
+            self.pos += 1;
+            let expr = self.parse_or()?;
+            self.skip_whitespace();
+            if !self.rest().starts_with(')') {
+                return Err(self.error("expected ')'"));
+            }
+            self.pos += 1;
+            return Ok(expr);
+        }
+        self.parse_term()
+    }
+
+    fn parse_term(&mut self) -> Result<Expr, ParseError> {
+        let start = self.pos;
+        let name_len = self
+            .rest()
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(self.rest().len());
+        let field = match self.rest()[..name_len].to_ascii_lowercase().as_str() {
+            "city" => Field::City,
+            "region" => Field::Region,
+            "country" => Field::Country,
+            "population" => Field::Population,
+            "" => return Err(self.error("expected a column name")),
+            _ => return Err(self.error("unknown column")),
+        };
+        self.pos += name_len;
+        self.skip_whitespace();
+
+        let operators = [
+            ("!=", Some(Cmp::Ne)),
+            ("<=", Some(Cmp::Le)),
+            (">=", Some(Cmp::Ge)),
+            ("^=", None),
+            ("=", Some(Cmp::Eq)),
+            ("<", Some(Cmp::Lt)),
+            (">", Some(Cmp::Gt)),
+            ("~", None),
+        ];
+        let op_pos = self.pos;
+        let (op, cmp) = match operators.iter().find(|(op, _)| self.rest().starts_with(op)) {
+            Some(&(op, cmp)) => (op, cmp),
+            None => return Err(self.error("expected an operator")),
+        };
+        self.pos += op.len();
+        self.skip_whitespace();
+        let value_pos = self.pos;
+        let value = self.parse_value()?;
+
+        if field == Field::Population {
+            let count = value
+                .replace('_', "")
+                .parse::<u64>()
+                .map_err(|_| ParseError {
+                    pos: value_pos,
+                    msg: "population must be a whole number".to_string(),
+                })?;
+            return match cmp {
+                Some(cmp) => Ok(Expr::Population(cmp, count)),
+                None => Err(ParseError {
+                    pos: op_pos,
+                    msg: format!("'{}' cannot be used with population", op),
+                }),
+            };
+        }
+
+        match (op, cmp) {
+            ("^=", _) => Ok(Expr::Prefix(field, value)),
+            ("~", _) => Regex::new(&value)
+                .map(|regex| Expr::Matches(field, regex))
+                .map_err(|err| ParseError {
+                    pos: value_pos,
+                    msg: err.to_string(),
+                }),
+            (_, Some(cmp @ (Cmp::Eq | Cmp::Ne))) => Ok(Expr::Text(field, cmp, value)),
+            _ => Err(ParseError {
+                pos: start,
+                msg: format!("'{}' is only supported for population", op),
+            }),
+        }
+    }
+
+    // A value is either quoted with `"` or `'`, or runs up to the next
+    // whitespace or closing parenthesis. Inside quotes a backslash only
+    // escapes the quote character or another backslash, so regexes like
+    // `'\w+'` can be written as-is.
+    fn parse_value(&mut self) -> Result<String, ParseError> {
+        let mut chars = self.rest().char_indices();
+        let quote = match chars.next() {
+            Some((_, c @ ('"' | '\''))) => c,
+            Some(_) => {
+                let len = self
+                    .rest()
+                    .find(|c: char| c.is_whitespace() || c == ')')
+                    .unwrap_or(self.rest().len());
+                let value = self.rest()[..len].to_string();
+                self.pos += len;
+                return Ok(value);
+            }
+            None => return Err(self.error("expected a value")),
+        };
+
+        let mut value = String::new();
+        let mut escaped = false;
+        for (i, c) in chars {
+            if escaped {
+                if c != quote && c != '\\' {
+                    value.push('\\');
+                }
+                value.push(c);
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == quote {
+                self.pos += i + 1;
+                return Ok(value);
+            } else {
+                value.push(c);
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(city: &str, region: Option<&str>, population: Option<u64>) -> Record {
+        Record {
+            city: city.to_string(),
+            region: region.map(String::from),
+            country: "United States".to_string(),
+            population,
+            latitude: None,
+            longitude: None,
+            year: None,
+        }
+    }
+
+    #[test]
+    fn combines_terms() {
+        let expr = Expr::parse(r#"country="United States" and (population>100000 or city^=North)"#)
+            .unwrap();
+
+        assert!(expr.matches(&record("Springfield", Some("MA"), Some(152227))));
+        assert!(expr.matches(&record("Northbridge", Some("MA"), Some(14061))));
+        assert!(!expr.matches(&record("Concord", Some("NH"), Some(42605))));
+    }
+
+    #[test]
+    fn missing_values_never_match() {
+        let expr = Expr::parse("population<=100 || region=MA").unwrap();
+
+        assert!(!expr.matches(&record("Springfield", None, None)));
+        assert!(Expr::parse("not population>0").unwrap().matches(&record(
+            "Springfield",
+            None,
+            None
+        )));
+    }
+
+    #[test]
+    fn regex_on_city() {
+        let expr = Expr::parse(r"city ~ '^\w+borough$'").unwrap();
+
+        assert!(expr.matches(&record("Westborough", None, None)));
+        assert!(!expr.matches(&record("Northbridge", None, None)));
+    }
+
+    #[test]
+    fn reports_errors() {
+        assert_eq!(Expr::parse("size=3").unwrap_err().msg, "unknown column");
+        assert_eq!(Expr::parse("population>lots").unwrap_err().pos, 11);
+        assert!(Expr::parse("city<Boston").is_err());
+        assert!(Expr::parse("(city=Boston").is_err());
+        assert!(Expr::parse("city='Boston").is_err());
+    }
+
+    #[test]
+    fn reads_text_that_is_not_ascii() {
+        assert!(Expr::parse("city=a éé").is_err());
+        assert!(Expr::parse("city=a ééé").is_err());
+        assert!(Expr::parse("city=a oé").is_err());
+        assert!(Expr::parse("city=a and ü").is_err());
+        assert!(Expr::parse("city=a and not é").is_err());
+        let expr = Expr::parse("city=é").unwrap();
+        assert!(expr.matches(&record("é", None, None)));
+        let expr = Expr::parse("city=a or city=Zürich").unwrap();
+        assert!(expr.matches(&record("Zürich", None, None)));
+    }
+}
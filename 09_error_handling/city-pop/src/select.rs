@@ -0,0 +1,323 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use crate::fuzzy;
+use crate::ingest::Missing;
+use crate::PopulationCount;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortKey {
+    Population,
+    // Names sort ignoring case and accents, then by region and country.
+    City,
+    Country,
+}
+
+impl SortKey {
+    pub fn parse(name: &str) -> Option<SortKey> {
+        match name {
+            "population" => Some(SortKey::Population),
+            "city" => Some(SortKey::City),
+            "country" => Some(SortKey::Country),
+            _ => None,
+        }
+    }
+}
+
+// Which of the results to keep, and in which order: a page of `limit`
+// results after skipping `offset`. Results that compare equal keep their
+// input order, also with `descending`. Results without a population are
+// only kept with `Missing::Include`; they sort below every population.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Selection {
+    pub sort: Option<SortKey>,
+    pub descending: bool,
+    pub limit: Option<usize>,
+    pub offset: usize,
+    pub missing: Missing,
+}
+
+// The selected results, and how many results without a population came by,
+// whether they were kept or not.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Found {
+    pub rows: Vec<PopulationCount>,
+    pub missing: usize,
+    // Whether the results stopped coming once an unsorted page was full.
+    // `missing` then only counts those that came by before.
+    pub partial: bool,
+}
+
+impl Selection {
+    pub fn collector(&self) -> Collector {
+        Collector {
+            selection: *self,
+            seen: 0,
+            missing: 0,
+            kept: vec![],
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    pub fn apply<I: IntoIterator<Item = PopulationCount>>(&self, rows: I) -> Found {
+        let mut collector = self.collector();
+        for row in rows {
+            if collector.is_full() {
+                break;
+            }
+            collector.push(row);
+        }
+        collector.finish()
+    }
+
+    // How many results have to be held on to, if that is bounded.
+    fn window(&self) -> Option<usize> {
+        self.limit.map(|limit| self.offset.saturating_add(limit))
+    }
+}
+
+// A result with what it is ordered by.
+struct Entry {
+    key: Key,
+    seq: usize,
+    descending: bool,
+    row: PopulationCount,
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+    Count(Option<u64>),
+    Text(String, String, String),
+}
+
+impl Entry {
+    fn new(selection: &Selection, seq: usize, row: PopulationCount) -> Entry {
+        let key = match selection.sort {
+            Some(SortKey::Population) | None => Key::Count(row.count),
+            Some(SortKey::City) => Key::Text(
+                fuzzy::fold(&row.city),
+                row.region.as_deref().map(fuzzy::fold).unwrap_or_default(),
+                fuzzy::fold(&row.country),
+            ),
+            Some(SortKey::Country) => Key::Text(
+                fuzzy::fold(&row.country),
+                row.region.as_deref().map(fuzzy::fold).unwrap_or_default(),
+                fuzzy::fold(&row.city),
+            ),
+        };
+        Entry {
+            key,
+            seq,
+            descending: selection.descending,
+            row,
+        }
+    }
+}
+
+// Entries are ordered the way they are to be output.
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> Ordering {
+        let by_key = self.key.cmp(&other.key);
+        match self.descending {
+            true => by_key.reverse(),
+            false => by_key,
+        }
+        .then(self.seq.cmp(&other.seq))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+// Collects results one at a time. Only `offset + limit` of them are ever
+// held: unsorted, the first ones; sorted, the best ones so far in a heap
+// whose top is the worst of them, to be dropped when a better one comes.
+pub struct Collector {
+    selection: Selection,
+    seen: usize,
+    missing: usize,
+    // Unsorted results, in input order.
+    kept: Vec<PopulationCount>,
+    heap: BinaryHeap<Entry>,
+}
+
+impl Collector {
+    pub fn push(&mut self, row: PopulationCount) {
+        if row.count.is_none() {
+            self.missing += 1;
+            if self.selection.missing != Missing::Include {
+                return;
+            }
+        }
+        let seq = self.seen;
+        self.seen += 1;
+        if self.selection.sort.is_none() {
+            if !self.is_full() {
+                self.kept.push(row);
+            }
+            return;
+        }
+
+        let entry = Entry::new(&self.selection, seq, row);
+        match self.selection.window() {
+            Some(0) => {}
+            Some(window) if self.heap.len() == window => {
+                if entry < *self.heap.peek().unwrap() {
+                    self.heap.pop();
+                    self.heap.push(entry);
+                }
+            }
+            _ => self.heap.push(entry),
+        }
+    }
+
+    // Whether further results can make no difference. Only the case for
+    // unsorted selections, which keep the first results they get.
+    pub fn is_full(&self) -> bool {
+        self.selection.sort.is_none()
+            && self
+                .selection
+                .window()
+                .is_some_and(|window| self.kept.len() >= window)
+    }
+
+    // The number of results pushed so far, not counting those left out for
+    // having no population.
+    pub fn seen(&self) -> usize {
+        self.seen
+    }
+
+    pub fn finish(self) -> Found {
+        let full = self.is_full();
+        let sorted: Vec<PopulationCount> = match self.selection.sort {
+            None => self.kept,
+            Some(_) => self
+                .heap
+                .into_sorted_vec()
+                .into_iter()
+                .map(|entry| entry.row)
+                .collect(),
+        };
+        let rows = sorted
+            .into_iter()
+            .skip(self.selection.offset)
+            .take(self.selection.limit.unwrap_or(usize::MAX))
+            .collect();
+        Found {
+            rows,
+            missing: self.missing,
+            partial: full,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(city: &str, region: &str, count: u64) -> PopulationCount {
+        PopulationCount {
+            city: city.to_string(),
+            region: Some(region.to_string()),
+            country: "United States".to_string(),
+            count: Some(count),
+        }
+    }
+
+    fn rows() -> Vec<PopulationCount> {
+        vec![
+            row("Springfield", "MA", 152227),
+            row("Concord", "NH", 42605),
+            row("Springfield", "MO", 150443),
+            row("Ábrams", "WI", 42605),
+            row("Westborough", "MA", 29313),
+        ]
+    }
+
+    fn cities(selection: Selection) -> Vec<String> {
+        selection
+            .apply(rows())
+            .rows
+            .into_iter()
+            .map(|row| format!("{} {}", row.city, row.region.unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn sorts_and_pages() {
+        let by_population = Selection {
+            sort: Some(SortKey::Population),
+            descending: true,
+            limit: Some(3),
+            ..Selection::default()
+        };
+        assert_eq!(
+            cities(by_population),
+            vec!["Springfield MA", "Springfield MO", "Concord NH"]
+        );
+
+        // Ties keep their input order either way.
+        let ascending = Selection {
+            descending: false,
+            offset: 1,
+            ..by_population
+        };
+        assert_eq!(
+            cities(ascending),
+            vec!["Concord NH", "Ábrams WI", "Springfield MO"]
+        );
+
+        let by_city = Selection {
+            sort: Some(SortKey::City),
+            ..Selection::default()
+        };
+        assert_eq!(cities(by_city)[..2], ["Ábrams WI", "Concord NH"]);
+    }
+
+    #[test]
+    fn keeps_only_the_window() {
+        let selection = Selection {
+            sort: Some(SortKey::Population),
+            descending: true,
+            limit: Some(2),
+            offset: 1,
+            ..Selection::default()
+        };
+        let mut collector = selection.collector();
+        for count in 0..10_000 {
+            collector.push(row("X", "Y", count * 7919 % 10_007));
+            assert!(collector.heap.len() <= 3);
+        }
+        let counts: Vec<_> = collector
+            .finish()
+            .rows
+            .iter()
+            .map(|row| row.count.unwrap())
+            .collect();
+        assert_eq!(counts, vec![10_005, 10_004]);
+
+        let first = Selection {
+            limit: Some(2),
+            offset: 1,
+            ..Selection::default()
+        };
+        let mut collector = first.collector();
+        for row in rows() {
+            collector.push(row);
+        }
+        assert!(collector.is_full());
+        assert_eq!(collector.seen(), 5);
+        assert_eq!(cities(first), vec!["Concord NH", "Springfield MO"]);
+    }
+}
@@ -0,0 +1,363 @@
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::query::{Cmp, Expr, Field};
+use crate::{CityIndex, CliError, PopulationCount};
+
+// Page size of `/cities` unless the request asks for another one.
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 1000;
+
+// How long a client may take to send its request, or to take the response.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Requests have no body, so these bound what a client can make us read.
+const MAX_LINE: usize = 8 << 10;
+const MAX_HEADERS: usize = 100;
+
+// Connections answered at the same time, and how many more may wait for a
+// worker before new ones are turned away.
+const WORKERS: usize = 16;
+const QUEUE: usize = 64;
+
+// A minimal HTTP/1.1 server answering lookups from a dataset loaded once:
+//
+//   GET /health                 {"status": "ok", "cities": N}
+//   GET /cities?name=&country=&region=&limit=&offset=
+//
+// Every response is JSON and closes the connection.
+pub struct Server {
+    listener: TcpListener,
+    index: Arc<CityIndex>,
+}
+
+impl Server {
+    pub fn bind<A: ToSocketAddrs>(addr: A, index: CityIndex) -> Result<Server, CliError> {
+        Ok(Server {
+            listener: TcpListener::bind(addr)?,
+            index: Arc::new(index),
+        })
+    }
+
+    // The address actually bound, e.g. when asked for port 0.
+    pub fn local_addr(&self) -> Result<SocketAddr, CliError> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    // Answers requests on a fixed number of worker threads until accepting a
+    // connection fails. When every worker is busy and the queue is full, new
+    // connections get a 503 straight away.
+    pub fn run(&self) -> Result<(), CliError> {
+        let (sender, receiver) = mpsc::sync_channel::<TcpStream>(QUEUE);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..WORKERS {
+            let receiver = Arc::clone(&receiver);
+            let index = Arc::clone(&self.index);
+            thread::spawn(move || loop {
+                // The lock is only held while waiting for a connection.
+                let next = receiver.lock().unwrap().recv();
+                match next {
+                    // A client that goes away mid-request only affects itself.
+                    Ok(stream) => drop(handle(&index, stream)),
+                    Err(_) => return,
+                }
+            });
+        }
+
+        loop {
+            let (stream, _) = self.listener.accept()?;
+            if let Err(TrySendError::Full(stream)) = sender.try_send(stream) {
+                let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+                let _ = write_response(&stream, &Response::error(503, "server busy"));
+            }
+        }
+    }
+}
+
+struct Response {
+    status: u16,
+    body: Value,
+}
+
+impl Response {
+    fn ok(body: Value) -> Response {
+        Response { status: 200, body }
+    }
+
+    fn error(status: u16, msg: &str) -> Response {
+        Response {
+            status,
+            body: json!({ "error": msg }),
+        }
+    }
+}
+
+fn handle(index: &CityIndex, stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+
+    let response = match read_request(&mut reader)? {
+        Ok(request_line) => {
+            let mut parts = request_line.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some(method), Some(target)) => respond(index, method, target),
+                _ => Response::error(400, "malformed request"),
+            }
+        }
+        Err(response) => response,
+    };
+    write_response(&stream, &response)
+}
+
+// Reads the request line and skips the headers, which are not needed but
+// have to be read before answering. A request over the size limits gets the
+// error response to send instead.
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Result<String, Response>> {
+    let Some(request_line) = read_line(reader)? else {
+        return Ok(Err(Response::error(400, "request line too long")));
+    };
+    // One more line than there may be headers, for the blank line after them.
+    for _ in 0..=MAX_HEADERS {
+        match read_line(reader)? {
+            Some(header) if header.trim_end().is_empty() => return Ok(Ok(request_line)),
+            Some(_) => {}
+            None => return Ok(Err(Response::error(431, "header line too long"))),
+        }
+    }
+    Ok(Err(Response::error(431, "too many headers")))
+}
+
+// Reads a line of at most `MAX_LINE` bytes, or `None` if it is longer. The
+// line is empty at the end of the input.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<String>> {
+    let mut line = vec![];
+    reader
+        .by_ref()
+        .take(MAX_LINE as u64)
+        .read_until(b'\n', &mut line)?;
+    if line.len() == MAX_LINE && !line.ends_with(b"\n") {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
+
+fn write_response<W: Write>(mut out: W, response: &Response) -> io::Result<()> {
+    let body = response.body.to_string();
+    write!(
+        out,
+        "HTTP/1.1 {} {}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        response.status,
+        reason(response.status),
+        body.len(),
+        body
+    )?;
+    out.flush()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+fn respond(index: &CityIndex, method: &str, target: &str) -> Response {
+    if method != "GET" {
+        return Response::error(405, "only GET is supported");
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let params = match parse_query(query) {
+        Some(params) => params,
+        None => return Response::error(400, "malformed query string"),
+    };
+    match path {
+        "/health" => Response::ok(json!({ "status": "ok", "cities": index.len() })),
+        "/cities" => cities(index, &params),
+        _ => Response::error(404, "no such endpoint"),
+    }
+}
+
+// `/cities`: cities matching every given parameter, a page at a time. Names
+// and countries match regardless of case and accents, regions exactly.
+fn cities(index: &CityIndex, params: &[(String, String)]) -> Response {
+    let mut filter = None;
+    let mut limit = DEFAULT_LIMIT;
+    let mut offset = 0;
+
+    for (key, value) in params {
+        let term = match key.as_str() {
+            "name" => Expr::folded(Field::City, value),
+            "country" => Expr::folded(Field::Country, value),
+            "region" => Expr::Text(Field::Region, Cmp::Eq, value.clone()),
+            "limit" | "offset" => {
+                // An empty page would never get any further.
+                let n = match value.parse::<usize>() {
+                    Ok(0) if key == "limit" => None,
+                    Ok(n) => Some(n),
+                    Err(_) => None,
+                };
+                let Some(n) = n else {
+                    return Response::error(400, &format!("invalid {} '{}'", key, value));
+                };
+                if key == "limit" {
+                    limit = n.min(MAX_LIMIT);
+                } else {
+                    offset = n;
+                }
+                continue;
+            }
+            _ => return Response::error(400, &format!("unknown parameter '{}'", key)),
+        };
+        filter = Expr::and(filter, Some(term));
+    }
+
+    let found = match filter {
+        Some(ref filter) => index.search(filter),
+        None => Ok(index.iter().filter_map(PopulationCount::of).collect()),
+    };
+    let (found, suggestions) = match found {
+        Ok(found) => (found, vec![]),
+        Err(CliError::NotFound(suggestions)) => (vec![], suggestions),
+        Err(err) => return Response::error(400, &err.to_string()),
+    };
+
+    let total = found.len();
+    let page: Vec<_> = found.into_iter().skip(offset).take(limit).collect();
+    let next_offset = (offset + page.len() < total).then_some(offset + page.len());
+    Response::ok(json!({
+        "total": total,
+        "offset": offset,
+        "limit": limit,
+        "next_offset": next_offset,
+        "results": page,
+        "suggestions": suggestions,
+    }))
+}
+
+// Splits `a=1&b=2` into decoded pairs, in order.
+fn parse_query(query: &str) -> Option<Vec<(String, String)>> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Some((percent_decode(key)?, percent_decode(value)?))
+        })
+        .collect()
+}
+
+// Decodes `%XX` escapes and `+` for a space. `None` if an escape is broken or
+// the result is not UTF-8.
+fn percent_decode(input: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(input.len());
+    let mut rest = input.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = std::str::from_utf8(rest.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &rest[2..];
+            }
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index() -> CityIndex {
+        let data = "city,region,country,population\n\
+                    São Paulo,SP,Brazil,12325232\n\
+                    Springfield,MA,United States,152227\n\
+                    Springfield,MO,United States,150443\n\
+                    Springfield,OR,United States,56032\n";
+        CityIndex::from_reader(data.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn decodes_query_strings() {
+        assert_eq!(
+            parse_query("name=S%C3%A3o+Paulo&limit=2").unwrap(),
+            vec![
+                ("name".to_string(), "São Paulo".to_string()),
+                ("limit".to_string(), "2".to_string())
+            ]
+        );
+        assert!(parse_query("name=%E").is_none());
+        assert!(parse_query("name=%FF").is_none());
+    }
+
+    #[test]
+    fn pages_through_results() {
+        let index = index();
+        let response = respond(&index, "GET", "/cities?name=springfield&limit=2");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body["total"], 3);
+        assert_eq!(response.body["results"][1]["region"], "MO");
+        assert_eq!(response.body["next_offset"], 2);
+
+        let response = respond(&index, "GET", "/cities?name=springfield&limit=2&offset=2");
+        assert_eq!(response.body["results"][0]["region"], "OR");
+        assert_eq!(response.body["next_offset"], Value::Null);
+
+        let response = respond(&index, "GET", "/cities?name=springfield&limit=0");
+        assert_eq!(response.status, 400);
+        assert_eq!(response.body["error"], "invalid limit '0'");
+        let response = respond(&index, "GET", "/cities?name=springfield&offset=0");
+        assert_eq!(response.status, 200);
+    }
+
+    fn request(data: &[u8]) -> Result<String, u16> {
+        read_request(&mut io::Cursor::new(data))
+            .unwrap()
+            .map_err(|response| response.status)
+    }
+
+    #[test]
+    fn limits_request_size() {
+        assert_eq!(
+            request(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n"),
+            Ok("GET / HTTP/1.1\r\n".to_string())
+        );
+
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE));
+        assert_eq!(request(long.as_bytes()), Err(400));
+        let long = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_LINE));
+        assert_eq!(request(long.as_bytes()), Err(431));
+
+        let headers = "X: y\r\n".repeat(MAX_HEADERS);
+        let request_with =
+            |headers: &str| request(format!("GET / HTTP/1.1\r\n{}\r\n", headers).as_bytes());
+        assert!(request_with(&headers).is_ok());
+        assert_eq!(request_with(&format!("{}X: y\r\n", headers)), Err(431));
+    }
+
+    #[test]
+    fn rejects_bad_requests() {
+        let index = index();
+        assert_eq!(respond(&index, "POST", "/cities").status, 405);
+        assert_eq!(respond(&index, "GET", "/towns").status, 404);
+        assert_eq!(respond(&index, "GET", "/cities?limit=all").status, 400);
+        assert_eq!(respond(&index, "GET", "/cities?town=Paris").status, 400);
+    }
+}
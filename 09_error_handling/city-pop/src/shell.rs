@@ -0,0 +1,386 @@
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::fuzzy;
+use crate::output::{self, Format};
+use crate::query::{Expr, Field};
+use crate::stats::GroupBy;
+use crate::{CityIndex, CliError, PopulationCount};
+
+const COMMANDS: [&str; 8] = [
+    "find", "top", "stats", "filter", "export", "help", "quit", "exit",
+];
+
+const HELP: &str = "\
+Commands:
+  find <city>                   Cities with this name, ignoring case and accents.
+  top [<n>]                     The n (default 10) largest cities.
+  stats [country|region] [<n>]  Statistics per group and the n largest cities.
+  filter <expr>                 Only look at cities matching a query from now on,
+                                e.g. filter country=\"United States\" and population>50000
+  filter                        Show the current filter.
+  filter clear                  Look at every city again.
+  export <path> [<format>]      Write the matching cities to a file as csv, json or
+                                ndjson, by default after the extension of <path>.
+  help                          Show this message.
+  quit                          Leave the shell; so does Ctrl-D.
+";
+
+// The state of an interactive session: the dataset and the filter that
+// every command applies.
+pub struct Session {
+    index: CityIndex,
+    // The filter and the text it was parsed from.
+    filter: Option<(Expr, String)>,
+}
+
+impl Session {
+    pub fn new(index: CityIndex) -> Session {
+        Session {
+            index,
+            filter: None,
+        }
+    }
+
+    fn filter(&self) -> Option<&Expr> {
+        self.filter.as_ref().map(|(expr, _)| expr)
+    }
+
+    // Runs one line of input, writing any results to `out`. Returns whether
+    // the session should go on.
+    pub fn execute<W: Write>(&mut self, line: &str, out: &mut W) -> Result<bool, CliError> {
+        let line = line.trim();
+        let (command, arg) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let arg = arg.trim();
+        let args: Vec<&str> = arg.split_whitespace().collect();
+
+        match command {
+            "" => {}
+            "find" if !arg.is_empty() => {
+                let mut filter = Expr::folded(Field::City, arg);
+                if let Some(current) = self.filter() {
+                    filter = Expr::And(Box::new(filter), Box::new(current.clone()));
+                }
+                match self.index.search(&filter) {
+                    Ok(found) => output::write_rows(&mut *out, Format::Table, &found)?,
+                    Err(err @ CliError::NotFound(_)) => writeln!(out, "{}", err)?,
+                    Err(err) => return Err(err),
+                }
+            }
+            "top" if args.len() <= 1 => {
+                let n = count(args.first(), 10)?;
+                let summary = self.index.summarize(self.filter(), GroupBy::Country, n)?;
+                output::write_rows(&mut *out, Format::Table, &summary.largest)?;
+            }
+            "stats" if args.len() <= 2 => {
+                let (by, n) = match args.first().and_then(|name| GroupBy::parse(name)) {
+                    Some(by) => (by, args.get(1)),
+                    None if args.len() <= 1 => (GroupBy::Country, args.first()),
+                    None => return Err(CliError::Args(format!("cannot group by '{}'", args[0]))),
+                };
+                let n = count(n, 5)?;
+                write!(out, "{}", self.index.summarize(self.filter(), by, n)?)?;
+            }
+            "filter" => match arg {
+                "" => match self.filter {
+                    Some((_, ref text)) => writeln!(out, "{}", text)?,
+                    None => writeln!(out, "No filter, every city is included.")?,
+                },
+                "clear" => self.filter = None,
+                _ => {
+                    let expr = Expr::parse(arg)?;
+                    let matching = self.index.filter(&expr).count();
+                    self.filter = Some((expr, arg.to_string()));
+                    writeln!(out, "{} of {} cities match.", matching, self.index.len())?;
+                }
+            },
+            "export" if (1..=2).contains(&args.len()) => {
+                let path = Path::new(args[0]);
+                let format = match args.get(1) {
+                    Some(name) => export_format(name)?,
+                    None => export_format(
+                        path.extension()
+                            .and_then(|ext| ext.to_str())
+                            .unwrap_or("csv"),
+                    )?,
+                };
+                let rows: Vec<PopulationCount> = match self.filter() {
+                    Some(filter) => self
+                        .index
+                        .filter(filter)
+                        .filter_map(PopulationCount::of)
+                        .collect(),
+                    None => self.index.iter().filter_map(PopulationCount::of).collect(),
+                };
+                output::write_rows(io::BufWriter::new(File::create(path)?), format, &rows)?;
+                writeln!(out, "Wrote {} cities to {}.", rows.len(), path.display())?;
+            }
+            "help" => write!(out, "{}", HELP)?,
+            "quit" | "exit" => return Ok(false),
+            _ if COMMANDS.contains(&command) => {
+                return Err(CliError::Args(format!(
+                    "wrong arguments for '{}', see 'help'",
+                    command
+                )))
+            }
+            _ => {
+                return Err(CliError::Args(format!(
+                    "unknown command '{}', see 'help'",
+                    command
+                )))
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn count(arg: Option<&&str>, default: usize) -> Result<usize, CliError> {
+    match arg {
+        None => Ok(default),
+        Some(arg) => arg
+            .parse()
+            .map_err(|_| CliError::Args(format!("expected a number, not '{}'", arg))),
+    }
+}
+
+fn export_format(name: &str) -> Result<Format, CliError> {
+    match Format::parse(name) {
+        Some(format @ (Format::Csv | Format::Json | Format::Ndjson)) => Ok(format),
+        _ => Err(CliError::Args(format!(
+            "cannot export as '{}', use csv, json or ndjson",
+            name
+        ))),
+    }
+}
+
+// Tab completion of command names, and of the city and country names in the
+// dataset for their arguments.
+pub struct Completion {
+    names: Vec<String>,
+}
+
+impl Completion {
+    pub fn new(index: &CityIndex) -> Completion {
+        let names: BTreeSet<&str> = index
+            .iter()
+            .flat_map(|record| [record.city.as_str(), record.country.as_str()])
+            .collect();
+        Completion {
+            names: names.into_iter().map(String::from).collect(),
+        }
+    }
+
+    // The byte offset where the completed text starts and the replacements
+    // for it. Names are matched ignoring case and accents. After `find` the
+    // whole rest of the line is one name; elsewhere the word being typed is,
+    // and a name after an opening quote gets the closing one added.
+    pub fn candidates(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let line = &line[..pos];
+        let Some(space) = line.find(char::is_whitespace) else {
+            let commands = COMMANDS.iter().filter(|command| command.starts_with(line));
+            return (0, commands.map(|command| command.to_string()).collect());
+        };
+
+        // After `find`, names may contain spaces without being quoted.
+        let whole_line = line[..space] == *"find";
+        let (start, quoted) = if whole_line {
+            (line.len() - line[space..].trim_start().len(), false)
+        } else {
+            let start = line
+                .char_indices()
+                .rev()
+                .find(|&(_, c)| c.is_whitespace() || "=<>!(".contains(c))
+                .map_or(0, |(i, c)| i + c.len_utf8());
+            match line[start..].starts_with('"') {
+                true => (start + 1, true),
+                false => (start, false),
+            }
+        };
+
+        let prefix = fuzzy::fold(&line[start..]);
+        let found = self
+            .names
+            .iter()
+            .filter(|name| fuzzy::fold(name).starts_with(&prefix))
+            .filter(|name| whole_line || quoted || !name.contains(' '))
+            .map(|name| match quoted {
+                true => format!("{}\"", name),
+                false => name.clone(),
+            })
+            .collect();
+        (start, found)
+    }
+}
+
+impl Completer for Completion {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (start, candidates) = self.candidates(line, pos);
+        let pairs = candidates
+            .into_iter()
+            .map(|candidate| Pair {
+                display: candidate.clone(),
+                replacement: candidate,
+            })
+            .collect();
+        Ok((start, pairs))
+    }
+}
+
+impl Hinter for Completion {
+    type Hint = String;
+}
+
+impl Highlighter for Completion {}
+
+impl Validator for Completion {}
+
+impl Helper for Completion {}
+
+// Where the history of earlier sessions is kept, if there is a home directory.
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(".city_pop_history"))
+}
+
+// Reads commands from the terminal until `quit` or end of input. Errors of
+// single commands are printed and the session goes on.
+pub fn run(index: CityIndex) -> Result<(), CliError> {
+    let mut editor: Editor<Completion, DefaultHistory> = Editor::new().map_err(readline_error)?;
+    editor.set_helper(Some(Completion::new(&index)));
+    let history = history_path();
+    if let Some(ref path) = history {
+        // There is no history before the first session.
+        let _ = editor.load_history(path);
+    }
+
+    println!(
+        "Loaded {} cities. Type 'help' for a list of commands.",
+        index.len()
+    );
+    let mut session = Session::new(index);
+    let mut stdout = io::stdout();
+    loop {
+        let line = match editor.readline("city-pop> ") {
+            Ok(line) => line,
+            // Ctrl-C abandons the current line, Ctrl-D ends the session.
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(readline_error(err)),
+        };
+        if !line.trim().is_empty() {
+            editor
+                .add_history_entry(line.as_str())
+                .map_err(readline_error)?;
+        }
+        match session.execute(&line, &mut stdout) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => eprintln!("error: {}", err),
+        }
+    }
+
+    if let Some(ref path) = history {
+        editor.save_history(path).map_err(readline_error)?;
+    }
+    Ok(())
+}
+
+fn readline_error(err: ReadlineError) -> CliError {
+    match err {
+        ReadlineError::Io(err) => CliError::IoError(err),
+        err => CliError::IoError(io::Error::other(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> Session {
+        let data = "city,region,country,population\n\
+                    São Paulo,SP,Brazil,12325232\n\
+                    Springfield,MA,United States,152227\n\
+                    Springfield,MO,United States,150443\n\
+                    Concord,NH,United States,42605\n";
+        Session::new(CityIndex::from_reader(data.as_bytes()).unwrap())
+    }
+
+    fn run(session: &mut Session, line: &str) -> String {
+        let mut out = vec![];
+        assert!(session.execute(line, &mut out).unwrap());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn runs_commands_under_a_filter() {
+        let mut session = session();
+        assert!(run(&mut session, "find sao paulo").contains("12325232"));
+
+        assert_eq!(
+            run(&mut session, "filter country=\"United States\""),
+            "3 of 4 cities match.\n"
+        );
+        assert!(run(&mut session, "find São Paulo").starts_with("No matching cities"));
+        let top = run(&mut session, "top 1");
+        assert!(top.contains("152227") && !top.contains("150443"), "{}", top);
+        assert!(run(&mut session, "stats region").contains("MA"));
+
+        run(&mut session, "filter clear");
+        assert!(run(&mut session, "top 1").contains("São Paulo"));
+        assert!(!session.execute("quit", &mut vec![]).unwrap());
+    }
+
+    #[test]
+    fn rejects_bad_commands() {
+        let mut session = session();
+        for line in [
+            "frobnicate",
+            "top many",
+            "find",
+            "filter city=",
+            "export a.txt",
+            "filter city=a éé",
+        ] {
+            assert!(session.execute(line, &mut vec![]).is_err(), "{}", line);
+        }
+        // A bad filter leaves the session running, with no filter.
+        assert!(run(&mut session, "top 1").contains("São Paulo"));
+    }
+
+    #[test]
+    fn completes_commands_and_names() {
+        let completion = Completion::new(&session().index);
+        let complete = |line: &str| completion.candidates(line, line.len());
+
+        assert_eq!(complete("st"), (0, vec!["stats".to_string()]));
+        assert_eq!(complete("find sao"), (5, vec!["São Paulo".to_string()]));
+        assert_eq!(
+            complete("filter country=\"uni"),
+            (16, vec!["United States\"".to_string()])
+        );
+        assert_eq!(
+            complete("filter city=Spr"),
+            (12, vec!["Springfield".to_string()])
+        );
+        // Separators may be wider than a byte.
+        assert_eq!(
+            complete("filter city=São and\u{a0}Spr"),
+            (22, vec!["Springfield".to_string()])
+        );
+    }
+}
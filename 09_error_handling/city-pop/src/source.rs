@@ -0,0 +1,464 @@
+// Where a dataset is read from. `-f` takes a path, or a URL-like source:
+//
+//   file://<path>     a file, the same as just <path>
+//   gzip://<path>     a gzip-compressed file, as is a <path> ending in .gz
+//   http://<host>/..  a download, cached locally and revalidated by ETag
+use flate2::read::MultiGzDecoder;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, fmt};
+
+use crate::CliError;
+
+pub trait DataSource: fmt::Debug {
+    // Opens the data for reading from the start.
+    fn open(&self) -> Result<Box<dyn Read>, CliError>;
+
+    // The path of the data, which its format is guessed from. For compressed
+    // data this is the path without the `.gz`.
+    fn path(&self) -> &str;
+
+    // The file the data can be read from as is, if there is one. Only such
+    // files can be split up and read on several threads.
+    fn local_file(&self) -> Option<&Path> {
+        None
+    }
+}
+
+pub fn parse(spec: &str) -> Result<Box<dyn DataSource>, CliError> {
+    let (scheme, rest) = match spec.split_once("://") {
+        Some((scheme, rest)) => (Some(scheme.to_lowercase()), rest),
+        None => (None, spec),
+    };
+    match scheme.as_deref() {
+        None if rest.ends_with(".gz") => Ok(Box::new(GzipSource::new(rest))),
+        None | Some("file") => Ok(Box::new(FileSource::new(rest))),
+        Some("gzip") => Ok(Box::new(GzipSource::new(rest))),
+        Some("http") => Ok(Box::new(HttpSource::new(spec)?)),
+        Some("https") => Err(CliError::Args(format!(
+            "cannot read '{}': https is not supported, download the file first",
+            spec
+        ))),
+        Some(scheme) => Err(CliError::Args(format!(
+            "unknown data source '{}://', use a path or file://, gzip:// or http://",
+            scheme
+        ))),
+    }
+}
+
+#[derive(Debug)]
+pub struct FileSource {
+    path: String,
+}
+
+impl FileSource {
+    pub fn new(path: &str) -> FileSource {
+        FileSource {
+            path: path.to_string(),
+        }
+    }
+}
+
+impl DataSource for FileSource {
+    fn open(&self) -> Result<Box<dyn Read>, CliError> {
+        Ok(Box::new(File::open(&self.path)?))
+    }
+
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn local_file(&self) -> Option<&Path> {
+        Some(Path::new(&self.path))
+    }
+}
+
+#[derive(Debug)]
+pub struct GzipSource {
+    path: String,
+}
+
+impl GzipSource {
+    pub fn new(path: &str) -> GzipSource {
+        GzipSource {
+            path: path.to_string(),
+        }
+    }
+}
+
+impl DataSource for GzipSource {
+    fn open(&self) -> Result<Box<dyn Read>, CliError> {
+        // `gzip` writes one member per file it was given; read all of them.
+        let file = BufReader::new(File::open(&self.path)?);
+        Ok(Box::new(MultiGzDecoder::new(file)))
+    }
+
+    fn path(&self) -> &str {
+        self.path.strip_suffix(".gz").unwrap_or(&self.path)
+    }
+}
+
+// Redirects to follow before giving up.
+const MAX_REDIRECTS: usize = 5;
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub struct HttpSource {
+    url: String,
+    // The host with the port, if one was given, as sent in `Host`.
+    host: String,
+    port: u16,
+    // The path with the query string.
+    target: String,
+    cache: Option<PathBuf>,
+}
+
+impl HttpSource {
+    // A source for an `http://` URL, cached in the user's cache directory.
+    pub fn new(url: &str) -> Result<HttpSource, CliError> {
+        let invalid = || CliError::Args(format!("invalid URL '{}'", url));
+        let rest = url
+            .strip_prefix("http://")
+            .or_else(|| url.strip_prefix("HTTP://"))
+            .ok_or_else(invalid)?;
+        let (host, target) = match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('?') => (&rest[..i], format!("/{}", &rest[i..])),
+            Some(i) => (&rest[..i], rest[i..].to_string()),
+            None => (rest, "/".to_string()),
+        };
+        let port = match host.rsplit_once(':') {
+            Some((_, port)) => port.parse().map_err(|_| invalid())?,
+            None => 80,
+        };
+        if host.is_empty() || host.starts_with(':') {
+            return Err(invalid());
+        }
+        Ok(HttpSource {
+            url: url.to_string(),
+            host: host.to_string(),
+            port,
+            target,
+            cache: cache_dir(),
+        })
+    }
+
+    // Keeps downloads in `dir`, or nowhere.
+    pub fn with_cache(self, dir: Option<PathBuf>) -> HttpSource {
+        HttpSource { cache: dir, ..self }
+    }
+
+    // Where the data and the ETag it was sent with are kept.
+    fn cached(&self) -> Option<(PathBuf, PathBuf)> {
+        let dir = self.cache.as_ref()?;
+        let key = format!("{:016x}", fnv1a(self.url.as_bytes()));
+        Some((
+            dir.join(format!("{}.data", key)),
+            dir.join(format!("{}.etag", key)),
+        ))
+    }
+
+    fn download(&self) -> Result<Box<dyn Read>, CliError> {
+        let cached = self.cached();
+        // Only ask whether the data changed if there is data to fall back on.
+        let etag = cached
+            .as_ref()
+            .and_then(|(data, etag)| match data.exists() {
+                true => fs::read_to_string(etag).ok(),
+                false => None,
+            });
+
+        let mut source = self;
+        let mut redirected;
+        for _ in 0..=MAX_REDIRECTS {
+            let response = source.get(etag.as_deref())?;
+            match response.status {
+                200 => {}
+                304 if etag.is_some() => {
+                    let (data, _) = cached.unwrap();
+                    return Ok(Box::new(File::open(data)?));
+                }
+                301 | 302 | 303 | 307 | 308 => {
+                    let location = response.header("location").ok_or_else(|| {
+                        http_error(&source.url, "redirect without a Location".to_string())
+                    })?;
+                    redirected = HttpSource::new(&source.resolve(location))?;
+                    source = &redirected;
+                    continue;
+                }
+                status => {
+                    return Err(http_error(
+                        &source.url,
+                        format!("server responded {} {}", status, response.reason),
+                    ))
+                }
+            }
+
+            let etag = response.header("etag").map(str::to_string);
+            return match (cached, etag) {
+                (Some((data, etag_path)), Some(etag)) => {
+                    response.save(&source.url, &data)?;
+                    fs::write(etag_path, etag)?;
+                    Ok(Box::new(File::open(data)?))
+                }
+                // Without an ETag there is no telling whether a copy is
+                // still current, so there is no point keeping one.
+                _ => Ok(Box::new(response.body(&source.url))),
+            };
+        }
+        Err(http_error(&self.url, "too many redirects".to_string()))
+    }
+
+    // Resolves a `Location` header sent for this source: an absolute URL, a
+    // path on the same host, or a path relative to the target's directory.
+    fn resolve(&self, location: &str) -> String {
+        if location.contains("://") {
+            return location.to_string();
+        }
+        if let Some(rest) = location.strip_prefix("//") {
+            return format!("http://{}", rest);
+        }
+        if location.starts_with('/') {
+            return format!("http://{}{}", self.host, location);
+        }
+        let path = self.target.split('?').next().unwrap_or(&self.target);
+        let dir = &path[..path.rfind('/').map_or(0, |i| i + 1)];
+        format!("http://{}{}{}", self.host, dir, location)
+    }
+
+    fn get(&self, etag: Option<&str>) -> Result<Response, CliError> {
+        let port = self.port;
+        let host = self
+            .host
+            .strip_suffix(&format!(":{}", port))
+            .unwrap_or(&self.host);
+        let stream = TcpStream::connect((host, port))?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        // HTTP/1.0 keeps the response simple: no chunked encoding, and the
+        // body ends when the server closes the connection.
+        let mut request = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: city-pop\r\nAccept-Encoding: identity\r\n",
+            self.target, self.host
+        );
+        if let Some(etag) = etag {
+            request.push_str(&format!("If-None-Match: {}\r\n", etag));
+        }
+        request.push_str("\r\n");
+        (&stream).write_all(request.as_bytes())?;
+
+        Response::read(BufReader::new(stream)).map_err(|err| match err {
+            CliError::IoError(err) if err.kind() == io::ErrorKind::InvalidData => {
+                http_error(&self.url, err.to_string())
+            }
+            err => err,
+        })
+    }
+}
+
+impl DataSource for HttpSource {
+    fn open(&self) -> Result<Box<dyn Read>, CliError> {
+        self.download()
+    }
+
+    fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or(&self.target)
+    }
+}
+
+struct Response {
+    status: u16,
+    reason: String,
+    // Header names are lowercase.
+    headers: Vec<(String, String)>,
+    body: BufReader<TcpStream>,
+}
+
+impl Response {
+    fn read(mut input: BufReader<TcpStream>) -> Result<Response, CliError> {
+        let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed response");
+        let mut line = String::new();
+        input.read_line(&mut line)?;
+        let mut parts = line.trim_end().splitn(3, ' ');
+        let status = match (parts.next(), parts.next()) {
+            (Some(version), Some(status)) if version.starts_with("HTTP/") => {
+                status.parse().map_err(|_| malformed())?
+            }
+            _ => return Err(malformed().into()),
+        };
+        let reason = parts.next().unwrap_or("").to_string();
+
+        let mut headers = vec![];
+        loop {
+            line.clear();
+            if input.read_line(&mut line)? == 0 {
+                return Err(malformed().into());
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').ok_or_else(malformed)?;
+            headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+        }
+        Ok(Response {
+            status,
+            reason,
+            headers,
+            body: input,
+        })
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // The body, which fails to read if it breaks off before the length the
+    // server gave, so that it cannot pass for the whole dataset.
+    fn body(self, url: &str) -> Body {
+        let expected = self
+            .header("content-length")
+            .and_then(|len| len.parse().ok());
+        Body {
+            input: self.body.take(expected.unwrap_or(u64::MAX)),
+            expected,
+            url: url.to_string(),
+        }
+    }
+
+    // Writes the body to `path`. A download that breaks off is not kept.
+    fn save(self, url: &str, path: &Path) -> Result<(), CliError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let partial = path.with_extension("part");
+        let mut file = File::create(&partial)?;
+        if let Err(err) = io::copy(&mut self.body(url), &mut file) {
+            fs::remove_file(&partial)?;
+            return Err(err.into());
+        }
+        fs::rename(partial, path)?;
+        Ok(())
+    }
+}
+
+struct Body {
+    input: io::Take<BufReader<TcpStream>>,
+    // The Content-Length, if the server sent one.
+    expected: Option<u64>,
+    url: String,
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.input.read(buf)?;
+        if let (0, false, Some(expected)) = (read, buf.is_empty(), self.expected) {
+            let remaining = self.input.limit();
+            if remaining > 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "GET {}: download ended after {} of {} bytes",
+                        self.url,
+                        expected - remaining,
+                        expected
+                    ),
+                ));
+            }
+        }
+        Ok(read)
+    }
+}
+
+fn http_error(url: &str, msg: String) -> CliError {
+    CliError::IoError(io::Error::other(format!("GET {}: {}", url, msg)))
+}
+
+// `$XDG_CACHE_HOME/city-pop`, else `~/.cache/city-pop`.
+fn cache_dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => Path::new(&env::var_os("HOME")?).join(".cache"),
+    };
+    Some(base.join("city-pop"))
+}
+
+// The cache is keyed by URL. FNV-1a is stable across Rust releases, unlike
+// the standard library's hasher.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::process;
+
+    #[test]
+    fn parses_sources() {
+        let source = parse("file:///data/cities.json").unwrap();
+        assert_eq!(source.path(), "/data/cities.json");
+        assert_eq!(source.local_file(), Some(Path::new("/data/cities.json")));
+        assert_eq!(parse("cities.csv").unwrap().path(), "cities.csv");
+
+        let source = parse("cities.ndjson.gz").unwrap();
+        assert_eq!(source.path(), "cities.ndjson");
+        assert_eq!(source.local_file(), None);
+        assert_eq!(parse("gzip://a/b.csv.gz").unwrap().path(), "a/b.csv");
+
+        let source = HttpSource::new("http://example.com:8000/pop/cities.csv?v=2").unwrap();
+        assert_eq!(source.host, "example.com:8000");
+        assert_eq!(source.port, 8000);
+        assert_eq!(source.target, "/pop/cities.csv?v=2");
+        assert_eq!(source.path(), "/pop/cities.csv");
+        assert_eq!(HttpSource::new("http://example.com").unwrap().target, "/");
+
+        let source = HttpSource::new("http://example.com/pop/2020/cities.csv?v=2").unwrap();
+        assert_eq!(
+            source.resolve("cities.csv"),
+            "http://example.com/pop/2020/cities.csv"
+        );
+        assert_eq!(source.resolve("/a.csv"), "http://example.com/a.csv");
+        assert_eq!(
+            source.resolve("//mirror.org/a.csv"),
+            "http://mirror.org/a.csv"
+        );
+        assert_eq!(source.resolve("http://b.org/a.csv"), "http://b.org/a.csv");
+
+        for spec in ["https://example.com/a.csv", "ftp://x/a.csv", "http://:80/"] {
+            assert_eq!(parse(spec).unwrap_err().exit_code(), 2, "{}", spec);
+        }
+    }
+
+    #[test]
+    fn reads_gzip_files() {
+        let path = env::temp_dir().join(format!("city-pop-{}.csv.gz", process::id()));
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        encoder.write_all(b"city,country,population\n").unwrap();
+        encoder.finish().unwrap();
+        // A second member, as `cat a.gz b.gz` makes.
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(b"Concord,United States,42605\n").unwrap();
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&encoder.finish().unwrap()).unwrap();
+
+        let mut data = String::new();
+        let source = parse(&format!("gzip://{}", path.display())).unwrap();
+        source.open().unwrap().read_to_string(&mut data).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            data,
+            "city,country,population\nConcord,United States,42605\n"
+        );
+    }
+}
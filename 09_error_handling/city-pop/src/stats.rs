@@ -0,0 +1,270 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt;
+
+use crate::ingest::RecordStream;
+use crate::output;
+use crate::query::Expr;
+use crate::{check_columns, CliError, PopulationCount, Record};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GroupBy {
+    Country,
+    Region,
+}
+
+impl GroupBy {
+    pub fn parse(name: &str) -> Option<GroupBy> {
+        match name {
+            "country" => Some(GroupBy::Country),
+            "region" => Some(GroupBy::Region),
+            _ => None,
+        }
+    }
+
+    fn key(&self, record: &Record) -> String {
+        match *self {
+            GroupBy::Country => record.country.clone(),
+            GroupBy::Region => record.region.clone().unwrap_or_else(|| "-".to_string()),
+        }
+    }
+}
+
+pub struct GroupStats {
+    pub name: String,
+    pub cities: usize,
+    pub total: u64,
+    pub mean: f64,
+    pub median: f64,
+}
+
+pub struct Summary {
+    pub by: GroupBy,
+    // Number of rows that matched the filter, with or without a population.
+    pub rows: usize,
+    // Rows that matched but have no population and so take no part in the
+    // statistics below.
+    pub missing: usize,
+    pub groups: Vec<GroupStats>,
+    // The largest cities, biggest first.
+    pub largest: Vec<PopulationCount>,
+}
+
+// Entries of the top-N heap. Ordered by population, and by position in the
+// input on ties so that earlier rows win.
+struct Ranked(u64, Reverse<usize>, PopulationCount);
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Ranked) -> bool {
+        (self.0, self.1) == (other.0, other.1)
+    }
+}
+
+impl Eq for Ranked {}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Ranked) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Ranked) -> std::cmp::Ordering {
+        (self.0, self.1).cmp(&(other.0, other.1))
+    }
+}
+
+fn median(counts: &mut [u64]) -> f64 {
+    counts.sort_unstable();
+    let mid = counts.len() / 2;
+    if counts.len().is_multiple_of(2) {
+        (counts[mid - 1] as f64 + counts[mid] as f64) / 2.0
+    } else {
+        counts[mid] as f64
+    }
+}
+
+// Computes the summary in a single pass over the CSV. Only the population
+// counts of each group (for the median) and the `top` largest cities are kept
+// in memory. Like a region filter, grouping by region needs a region column.
+pub fn summarize<S: RecordStream>(
+    records: &mut S,
+    filter: Option<&Expr>,
+    by: GroupBy,
+    top: usize,
+) -> Result<Summary, CliError> {
+    if let Some(filter) = filter {
+        check_columns(records, filter)?;
+    }
+    if by == GroupBy::Region && !records.has_column("region") {
+        return Err(CliError::MissingColumn("region".to_string()));
+    }
+    summarize_records(records.by_ref(), filter, by, top)
+}
+
+// `summarize` for records from anywhere, e.g. already in memory.
+pub fn summarize_records<I: Iterator<Item = Result<Record, CliError>>>(
+    records: I,
+    filter: Option<&Expr>,
+    by: GroupBy,
+    top: usize,
+) -> Result<Summary, CliError> {
+    let mut rows = 0;
+    let mut missing = 0;
+    let mut groups: HashMap<String, Vec<u64>> = HashMap::new();
+    let mut heap = BinaryHeap::with_capacity(top + 1);
+
+    for (i, result) in records.enumerate() {
+        let record = result?;
+        if !filter.is_none_or(|filter| filter.matches(&record)) {
+            continue;
+        }
+        rows += 1;
+
+        let count = match record.population {
+            Some(count) => count,
+            None => {
+                missing += 1;
+                continue;
+            }
+        };
+        groups.entry(by.key(&record)).or_default().push(count);
+
+        if top > 0 {
+            heap.push(Reverse(Ranked(
+                count,
+                Reverse(i),
+                PopulationCount {
+                    city: record.city,
+                    region: record.region,
+                    country: record.country,
+                    count: Some(count),
+                },
+            )));
+            if heap.len() > top {
+                heap.pop();
+            }
+        }
+    }
+
+    let mut groups: Vec<GroupStats> = groups
+        .into_iter()
+        .map(|(name, mut counts)| {
+            let total = counts.iter().sum();
+            GroupStats {
+                name,
+                cities: counts.len(),
+                total,
+                mean: total as f64 / counts.len() as f64,
+                median: median(&mut counts),
+            }
+        })
+        .collect();
+    groups.sort_by(|a, b| b.total.cmp(&a.total).then_with(|| a.name.cmp(&b.name)));
+
+    let largest = heap
+        .into_sorted_vec()
+        .into_iter()
+        .map(|Reverse(Ranked(_, _, pop))| pop)
+        .collect();
+
+    Ok(Summary {
+        by,
+        rows,
+        missing,
+        groups,
+        largest,
+    })
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Rows: {} ({} without population)",
+            self.rows, self.missing
+        )?;
+
+        let label = match self.by {
+            GroupBy::Country => "country",
+            GroupBy::Region => "region",
+        };
+        let width = self
+            .groups
+            .iter()
+            .map(|group| group.name.chars().count())
+            .chain(Some(label.len()))
+            .max()
+            .unwrap_or(0);
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:<width$}  {:>8}  {:>12}  {:>12}  {:>12}",
+            label, "cities", "total", "mean", "median"
+        )?;
+        for group in &self.groups {
+            writeln!(
+                f,
+                "{:<width$}  {:>8}  {:>12}  {:>12.1}  {:>12.1}",
+                group.name, group.cities, group.total, group.mean, group.median
+            )?;
+        }
+
+        if !self.largest.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Largest cities:")?;
+            for (rank, pop) in self.largest.iter().enumerate() {
+                write!(f, "{:>4}. {}", rank + 1, pop.city)?;
+                if let Some(ref region) = pop.region {
+                    write!(f, ", {}", region)?;
+                }
+                writeln!(f, ", {}: {}", pop.country, output::count(pop.count))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::{CsvOptions, Records};
+
+    const SMALLPOP: &str = include_str!("../smallpop.csv");
+
+    fn records(data: &str) -> Records<&[u8]> {
+        CsvOptions::default().records(data.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn groups_by_region() {
+        let summary = summarize(&mut records(SMALLPOP), None, GroupBy::Region, 3).unwrap();
+        let ma = &summary.groups[0];
+
+        assert_eq!(summary.rows, 10);
+        assert_eq!(summary.missing, 0);
+        assert_eq!(ma.name, "MA");
+        assert_eq!(ma.cities, 5);
+        assert_eq!(ma.total, 243621);
+        assert_eq!(ma.median, 29313.0);
+
+        let largest: Vec<_> = summary.largest.iter().map(|pop| pop.count).collect();
+        assert_eq!(largest, vec![Some(152227), Some(150443), Some(64325)]);
+    }
+
+    #[test]
+    fn counts_missing_population() {
+        let data = "city,country,population\na,X,10\nb,X,\nc,X,20\nd,Y,5\n";
+        let summary = summarize(&mut records(data), None, GroupBy::Country, 0).unwrap();
+
+        assert_eq!(summary.rows, 4);
+        assert_eq!(summary.missing, 1);
+        assert_eq!(summary.groups[0].median, 15.0);
+        assert!(summary.largest.is_empty());
+
+        match summarize(&mut records(data), None, GroupBy::Region, 0) {
+            Err(CliError::MissingColumn(column)) => assert_eq!(column, "region"),
+            result => panic!("unexpected {:?}", result.map(|summary| summary.rows)),
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::ingest::{Diagnostic, RecordStream};
+use crate::CliError;
+
+pub struct Report {
+    // Rows that were read into a `Record`.
+    pub valid: usize,
+    // Valid rows that have no population.
+    pub missing_population: usize,
+    // One entry per row that could not be read, in input order.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Report {
+    pub fn rows(&self) -> usize {
+        self.valid + self.diagnostics.len()
+    }
+
+    // How often each kind of problem occurred, by `Problem::name`.
+    pub fn counts(&self) -> BTreeMap<&'static str, usize> {
+        let mut counts = BTreeMap::new();
+        for diagnostic in &self.diagnostics {
+            *counts.entry(diagnostic.problem.name()).or_insert(0) += 1;
+        }
+        counts
+    }
+}
+
+// Reads every row of `records`, which should be lenient so that bad rows are
+// collected rather than stopping the scan.
+pub fn validate<S: RecordStream>(records: &mut S) -> Result<Report, CliError> {
+    let mut valid = 0;
+    let mut missing_population = 0;
+
+    for result in records.by_ref() {
+        let record = result?;
+        valid += 1;
+        if record.population.is_none() {
+            missing_population += 1;
+        }
+    }
+
+    Ok(Report {
+        valid,
+        missing_population,
+        diagnostics: records.diagnostics().to_vec(),
+    })
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Rows: {} ({} valid, {} invalid, {} without population)",
+            self.rows(),
+            self.valid,
+            self.diagnostics.len(),
+            self.missing_population
+        )?;
+        if self.diagnostics.is_empty() {
+            return Ok(());
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Problems by type:")?;
+        for (name, count) in self.counts() {
+            writeln!(f, "  {:<20}  {:>8}", name, count)?;
+        }
+
+        writeln!(f)?;
+        for diagnostic in &self.diagnostics {
+            writeln!(f, "{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::{CsvOptions, Problem};
+
+    #[test]
+    fn reports_every_bad_row() {
+        let data = "city,region,country,population\n\
+                    Springfield,MA,United States,152227\n\
+                    Springfield,MO,United States\n\
+                    Springfield,NJ,United States,lots\n\
+                    Concord,NH,United States,\n\
+                    Springfield,OH,United States,\"64,325\"\n";
+        let options = CsvOptions {
+            lenient: true,
+            ..CsvOptions::default()
+        };
+        let report = validate(&mut options.records(data.as_bytes()).unwrap()).unwrap();
+
+        assert_eq!(report.rows(), 5);
+        assert_eq!(report.valid, 3);
+        assert_eq!(report.missing_population, 1);
+        assert_eq!(
+            report.counts().into_iter().collect::<Vec<_>>(),
+            vec![("field-count", 1), ("invalid-population", 1)]
+        );
+
+        let bad = &report.diagnostics[1];
+        assert_eq!(bad.line, 4);
+        assert_eq!(bad.column.as_deref(), Some("population"));
+        assert_eq!(bad.value.as_deref(), Some("lots"));
+        assert_eq!(bad.problem, Problem::InvalidPopulation);
+        assert_eq!(
+            report.diagnostics[0].problem,
+            Problem::FieldCount {
+                expected: 4,
+                found: 3
+            }
+        );
+    }
+
+    #[test]
+    fn reports_invalid_utf8() {
+        let data = b"city,country,population\nS\xe3o Paulo,Brazil,12325232\n";
+        let options = CsvOptions {
+            lenient: true,
+            ..CsvOptions::default()
+        };
+        let report = validate(&mut options.records(&data[..]).unwrap()).unwrap();
+
+        assert_eq!(report.valid, 0);
+        assert_eq!(report.diagnostics[0].problem, Problem::InvalidUtf8);
+        assert_eq!(report.diagnostics[0].column.as_deref(), Some("city"));
+    }
+}
@@ -0,0 +1,123 @@
+use city_pop::ingest::CsvOptions;
+use city_pop::query::Expr;
+use city_pop::stats::GroupBy;
+use city_pop::{CityIndex, CliError};
+use std::fs::File;
+use std::path::Path;
+
+fn smallpop() -> File {
+    File::open(Path::new(env!("CARGO_MANIFEST_DIR")).join("smallpop.csv")).unwrap()
+}
+
+fn index() -> CityIndex {
+    CityIndex::from_reader(smallpop()).unwrap()
+}
+
+fn names<'a>(records: impl Iterator<Item = &'a city_pop::Record>) -> Vec<&'a str> {
+    records.map(|record| record.city.as_str()).collect()
+}
+
+#[test]
+fn loads_every_row() {
+    let index = index();
+
+    assert_eq!(index.len(), 10);
+    assert_eq!(index.total_population(), 572002);
+    assert_eq!(index.iter().next().unwrap().city, "Southborough");
+    assert_eq!((&index).into_iter().count(), 10);
+}
+
+#[test]
+fn finds_cities_by_name() {
+    let index = index();
+    let regions: Vec<_> = index
+        .find("SPRINGFIELD")
+        .map(|record| record.region.as_deref().unwrap())
+        .collect();
+
+    assert_eq!(regions, vec!["MA", "MO", "NJ", "OH", "OR"]);
+    assert_eq!(index.find("Boston").count(), 0);
+}
+
+#[test]
+fn selects_by_country_and_region() {
+    let index = index();
+
+    assert_eq!(index.in_country("United States").count(), 10);
+    assert_eq!(
+        names(index.in_region("MA")),
+        vec![
+            "Southborough",
+            "Northbridge",
+            "Westborough",
+            "Marlborough",
+            "Springfield"
+        ]
+    );
+}
+
+#[test]
+fn filters_with_queries() {
+    let index = index();
+    let filter = Expr::parse("region=MA and population>30000").unwrap();
+
+    assert_eq!(
+        names(index.filter(&filter)),
+        vec!["Marlborough", "Springfield"]
+    );
+}
+
+#[test]
+fn searches_like_the_csv_scan() {
+    let index = index();
+    let options = CsvOptions::default();
+
+    for query in ["city=Springfield", "population<20000", "not region=MA"] {
+        let filter = Expr::parse(query).unwrap();
+        let scanned = city_pop::search(&mut options.records(smallpop()).unwrap(), &filter);
+        assert_eq!(
+            index.search(&filter).unwrap(),
+            scanned.unwrap(),
+            "{}",
+            query
+        );
+    }
+
+    match index.search(&Expr::parse("city=Sprngfield").unwrap()) {
+        Err(CliError::NotFound(suggestions)) => assert_eq!(suggestions, vec!["Springfield"]),
+        other => panic!("expected no match, got {:?}", other),
+    }
+}
+
+#[test]
+fn ranks_and_summarizes() {
+    let index = index();
+    let largest: Vec<_> = index.largest(2).into_iter().map(|pop| pop.count).collect();
+    assert_eq!(largest, vec![Some(152227), Some(150443)]);
+
+    let summary = index.summarize(None, GroupBy::Region, 1).unwrap();
+    assert_eq!(summary.rows, 10);
+    assert_eq!(summary.groups[0].name, "MA");
+    assert_eq!(summary.groups[0].cities, 5);
+    assert_eq!(summary.largest[0].region.as_deref(), Some("MA"));
+}
+
+#[test]
+fn region_filters_need_a_region_column() {
+    let data = "city,country,population\nSpringfield,United States,152227\n";
+    let index = CityIndex::from_reader(data.as_bytes()).unwrap();
+
+    assert!(matches!(
+        index.search(&Expr::parse("region=MA").unwrap()),
+        Err(CliError::MissingColumn(_))
+    ));
+    assert!(matches!(
+        index.summarize(None, GroupBy::Region, 1),
+        Err(CliError::MissingColumn(_))
+    ));
+    let filter = Expr::parse("region=MA").unwrap();
+    assert!(matches!(
+        index.summarize(Some(&filter), GroupBy::Country, 1),
+        Err(CliError::MissingColumn(_))
+    ));
+}
@@ -0,0 +1,99 @@
+use city_pop::serve::Server;
+use city_pop::CityIndex;
+use serde_json::Value;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::thread;
+
+fn start() -> SocketAddr {
+    let data = File::open(Path::new(env!("CARGO_MANIFEST_DIR")).join("smallpop.csv")).unwrap();
+    let index = CityIndex::from_reader(data).unwrap();
+    let server = Server::bind("127.0.0.1:0", index).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+    addr
+}
+
+// Sends a GET request and returns the status code and the decoded body.
+fn get(addr: SocketAddr, target: &str) -> (u16, Value) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: {}\r\nAccept: application/json\r\n\r\n",
+        target, addr
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.contains("Content-Type: application/json"), "{}", head);
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+#[test]
+fn answers_health_checks() {
+    let (status, body) = get(start(), "/health");
+
+    assert_eq!(status, 200);
+    assert_eq!(body["status"], "ok");
+    assert_eq!(body["cities"], 10);
+}
+
+#[test]
+fn looks_up_cities() {
+    let addr = start();
+    let (status, body) = get(addr, "/cities?name=springfield&country=United+States");
+
+    assert_eq!(status, 200);
+    assert_eq!(body["total"], 5);
+    assert_eq!(body["results"][0]["city"], "Springfield");
+    assert_eq!(body["results"][0]["region"], "MA");
+    assert_eq!(body["results"][0]["population"], 152227);
+
+    let (_, body) = get(addr, "/cities?name=Springfield&region=OH");
+    assert_eq!(body["total"], 1);
+    assert_eq!(body["results"][0]["population"], 64325);
+}
+
+#[test]
+fn pages_through_results() {
+    let addr = start();
+    let mut regions = vec![];
+    let mut target = "/cities?name=Springfield&limit=2".to_string();
+    loop {
+        let (_, body) = get(addr, &target);
+        for city in body["results"].as_array().unwrap() {
+            regions.push(city["region"].as_str().unwrap().to_string());
+        }
+        match body["next_offset"].as_u64() {
+            Some(next) => target = format!("/cities?name=Springfield&limit=2&offset={}", next),
+            None => break,
+        }
+    }
+
+    assert_eq!(regions, vec!["MA", "MO", "NJ", "OH", "OR"]);
+}
+
+#[test]
+fn suggests_names_when_nothing_matches() {
+    let (status, body) = get(start(), "/cities?name=Sprngfield");
+
+    assert_eq!(status, 200);
+    assert_eq!(body["total"], 0);
+    assert_eq!(body["suggestions"][0], "Springfield");
+}
+
+#[test]
+fn reports_bad_requests() {
+    let addr = start();
+
+    let (status, body) = get(addr, "/cities?limit=lots");
+    assert_eq!(status, 400);
+    assert_eq!(body["error"], "invalid limit 'lots'");
+    assert_eq!(get(addr, "/cities?name=springfield&limit=0").0, 400);
+    assert_eq!(get(addr, "/towns").0, 404);
+}
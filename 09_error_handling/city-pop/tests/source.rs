@@ -0,0 +1,166 @@
+use city_pop::ingest::{CsvOptions, InputFormat};
+use city_pop::source::{self, DataSource, HttpSource};
+use city_pop::CityIndex;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{env, fs, process, thread};
+
+// What the stand-in server serves, and the requests it got.
+#[derive(Default)]
+struct Dataset {
+    etag: String,
+    body: String,
+    // The status of every response, in order.
+    responses: Vec<u16>,
+}
+
+// A stand-in for a dataset host: serves `/cities.csv` with an ETag and
+// answers `If-None-Match` with 304 when the data is unchanged.
+fn start(dataset: Arc<Mutex<Dataset>>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut input = BufReader::new(&stream);
+            let mut request_line = String::new();
+            input.read_line(&mut request_line).unwrap();
+            let mut if_none_match = None;
+            loop {
+                let mut line = String::new();
+                input.read_line(&mut line).unwrap();
+                if line.trim_end().is_empty() {
+                    break;
+                }
+                if let Some(value) = line.strip_prefix("If-None-Match:") {
+                    if_none_match = Some(value.trim().to_string());
+                }
+            }
+
+            let mut dataset = dataset.lock().unwrap();
+            let response = match request_line.split(' ').nth(1).unwrap() {
+                "/old.csv" => {
+                    "HTTP/1.0 301 Moved Permanently\r\nLocation: /cities.csv\r\n\r\n".to_string()
+                }
+                "/moved.csv" => "HTTP/1.0 302 Found\r\nLocation: cities.csv\r\n\r\n".to_string(),
+                // The connection closes before the promised length.
+                "/short.csv" => format!(
+                    "HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                    dataset.body.len() + 10,
+                    dataset.body
+                ),
+                "/cities.csv" if if_none_match.as_ref() == Some(&dataset.etag) => {
+                    dataset.responses.push(304);
+                    format!(
+                        "HTTP/1.0 304 Not Modified\r\nETag: {}\r\n\r\n",
+                        dataset.etag
+                    )
+                }
+                "/cities.csv" => {
+                    dataset.responses.push(200);
+                    format!(
+                        "HTTP/1.0 200 OK\r\nETag: {}\r\nContent-Length: {}\r\n\r\n{}",
+                        dataset.etag,
+                        dataset.body.len(),
+                        dataset.body
+                    )
+                }
+                _ => {
+                    dataset.responses.push(404);
+                    "HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string()
+                }
+            };
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+    addr
+}
+
+fn smallpop() -> String {
+    fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("smallpop.csv")).unwrap()
+}
+
+fn cache_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("city-pop-cache-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn read(source: &dyn DataSource) -> String {
+    let mut data = String::new();
+    source.open().unwrap().read_to_string(&mut data).unwrap();
+    data
+}
+
+#[test]
+fn downloads_and_revalidates_by_etag() {
+    let dataset = Arc::new(Mutex::new(Dataset {
+        etag: "\"v1\"".to_string(),
+        body: smallpop(),
+        ..Dataset::default()
+    }));
+    let addr = start(dataset.clone());
+    let cache = cache_dir("etag");
+    let source = HttpSource::new(&format!("http://{}/cities.csv", addr))
+        .unwrap()
+        .with_cache(Some(cache.clone()));
+
+    assert_eq!(read(&source), smallpop());
+    // The second time the server only confirms that the copy is current.
+    assert_eq!(read(&source), smallpop());
+    assert_eq!(dataset.lock().unwrap().responses, vec![200, 304]);
+
+    {
+        let mut dataset = dataset.lock().unwrap();
+        dataset.etag = "\"v2\"".to_string();
+        dataset.body = "city,country,population\nConcord,United States,42605\n".to_string();
+    }
+    let mut records = CsvOptions::default()
+        .open(
+            source.open().unwrap(),
+            InputFormat::from_path(source.path()),
+        )
+        .unwrap();
+    let index = CityIndex::read(&mut records).unwrap();
+    assert_eq!(index.len(), 1);
+    assert_eq!(dataset.lock().unwrap().responses, vec![200, 304, 200]);
+    fs::remove_dir_all(&cache).unwrap();
+}
+
+#[test]
+fn follows_redirects_and_reports_errors() {
+    let dataset = Arc::new(Mutex::new(Dataset {
+        etag: "\"v1\"".to_string(),
+        body: smallpop(),
+        ..Dataset::default()
+    }));
+    let addr = start(dataset.clone());
+
+    let source = source::parse(&format!("http://{}/old.csv", addr)).unwrap();
+    assert_eq!(source.path(), "/old.csv");
+    let source = HttpSource::new(&format!("http://{}/old.csv", addr))
+        .unwrap()
+        .with_cache(None);
+    assert_eq!(read(&source), smallpop());
+
+    let source = HttpSource::new(&format!("http://{}/moved.csv", addr))
+        .unwrap()
+        .with_cache(None);
+    assert_eq!(read(&source), smallpop());
+
+    let short = HttpSource::new(&format!("http://{}/short.csv", addr))
+        .unwrap()
+        .with_cache(None);
+    let mut data = String::new();
+    let err = short.open().unwrap().read_to_string(&mut data).unwrap_err();
+    assert!(err.to_string().contains("download ended after"), "{}", err);
+
+    let missing = HttpSource::new(&format!("http://{}/missing.csv", addr))
+        .unwrap()
+        .with_cache(None);
+    let err = missing.open().err().unwrap();
+    assert_eq!(err.exit_code(), 3);
+    assert!(err.to_string().contains("404 Not Found"), "{}", err);
+}